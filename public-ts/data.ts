@@ -30,6 +30,29 @@ type JSONPlotInfo = {
     name : number
 };
 
+//The server's timeline is in nanoseconds, we use seconds
+function toSeconds(t: number): number {
+    return t * 1e-9;
+}
+
+function toNanos(t: number): number {
+    return Math.max(0, Math.round(t * 1e9));
+}
+
+//Follow-up requests made when the server runs out of query budget, before giving up
+const MAX_PAGES = 16;
+
+//Tells the server which way we're scrolling, so that it can load the next chunks in advance
+function prefetchHint(last: TimeRange | undefined, start: number, end: number): string {
+    if(last !== undefined && start > last.min && end > last.max) {
+        return "&prefetch=right";
+    } else if(last !== undefined && start < last.min && end < last.max) {
+        return "&prefetch=left";
+    } else {
+        return "";
+    }
+}
+
 export class FrameInfo {
     public number: number;
     public start: number;
@@ -38,9 +61,9 @@ export class FrameInfo {
 
     public constructor(orig: JSONFrameInfo) {
         this.number = orig.number;
-        this.start = orig.end - orig.duration * 1e-9;
+        this.start = toSeconds(orig.end - orig.duration);
         this.duration = orig.duration;
-        this.end = orig.end;
+        this.end = toSeconds(orig.end);
     }
 }
 
@@ -60,8 +83,8 @@ export class ZoneInfo {
         this.entry_id = orig.entry_id;
         this.zone_uid = orig.zone_uid;
         this.color    = orig.color;
-        this.start    = orig.end - orig.duration * 1e-9;
-        this.end      = orig.end;
+        this.start    = toSeconds(orig.end - orig.duration);
+        this.end      = toSeconds(orig.end);
         this.duration = orig.duration;
         this.depth    = orig.depth;
         this.name     = orig.name;
@@ -82,7 +105,7 @@ export class HeapInfo {
     public used: number;
 
     public constructor(from: JSONPlotInfo) {
-        this.t = from.time;
+        this.t = toSeconds(from.time);
         this.used = from.value;
     }
 }
@@ -94,6 +117,8 @@ export type TimeRange = {
 
 type FrameDataQueryResult = {
     results: JSONFrameInfo[],
+    next_cursor?: number | null,
+    truncated: string | null,
     status: string
 }
 
@@ -106,7 +131,10 @@ type ZoneDataQueryResult = {
     strings: StringMap,
     thread_names: StringMap,
     zones: JSONZoneInfo[],
-    plots: JSONPlotInfo[]
+    next_cursor: number | null,
+    plots: JSONPlotInfo[],
+    plots_next_cursor: number | null,
+    truncated: string | null
 };
 
 export class DataProvider {
@@ -115,6 +143,8 @@ export class DataProvider {
     private strings: Map<number, string> = new Map();
     private threadNames: Map<number, string> = new Map();
     private dataEnd: number = 0.0;
+    private lastZoneRange: TimeRange | undefined = undefined;
+    private lastFrameRange: TimeRange | undefined = undefined;
 
     private readonly frameData: FrameInfo[] = [];
     private readonly detailedFrameData: FrameInfo[] = [];
@@ -179,53 +209,87 @@ export class DataProvider {
     }
 
     private async fetchZoneData(start: number, end: number): Promise<boolean> {
-        let data;
+        const prefetch = prefetchHint(this.lastZoneRange, start, end);
+        const range = { min: start, max: end };
+        const url = "/data/plots?start=" + toNanos(start) + "&end=" + toNanos(end) + prefetch;
+        let cursors = "";
+        let wantPlots = true;
 
-        try {
-            data = JSON.parse(await request("/data/plots?start=" + start + "&end=" + end));
-        } catch(err) {
-            console.error(err);
-            return false;
-        }
+        this.lastZoneRange = range;
 
-        if(data.status !== "ok") {
-            console.error(data.error);
-            return false;
-        }
+        //The server stops when the query takes too long, in which case we follow its cursors to get the rest
+        for(let page = 0;; page++) {
+            let data;
 
-        let safeData = data as ZoneDataQueryResult;
+            try {
+                data = JSON.parse(await request(url + cursors));
+            } catch(err) {
+                console.error(err);
+                return false;
+            }
 
-        for(const key in safeData.strings) {
-            this.strings.set(parseInt(key), safeData.strings[key]);
-        }
+            if(data.status !== "ok") {
+                console.error(data.error);
+                return false;
+            }
 
-        for(const key in safeData.thread_names) {
-            this.threadNames.set(parseInt(key), safeData.thread_names[key]);
-        }
+            if(this.lastZoneRange !== range) {
+                return false; //Superseded by a more recent query
+            }
 
-        for(const zi of this.perThread.values()) {
-            zi.length = 0;
-        }
+            const safeData = data as ZoneDataQueryResult;
 
-        for(const zd of safeData.zones) {
-            let dst = this.perThread.get(zd.thread);
+            if(page === 0) {
+                for(const zi of this.perThread.values()) {
+                    zi.length = 0;
+                }
 
-            if(dst === undefined) {
-                dst = [];
+                this.heapData.length = 0;
+            }
 
-                this.perThread.set(zd.thread, dst);
-                this.onNewThread.invoke(zd.thread);
+            for(const key in safeData.strings) {
+                this.strings.set(parseInt(key), safeData.strings[key]);
             }
 
-            dst.push(new ZoneInfo(zd));
-        }
+            for(const key in safeData.thread_names) {
+                this.threadNames.set(parseInt(key), safeData.thread_names[key]);
+            }
+
+            for(const zd of safeData.zones) {
+                let dst = this.perThread.get(zd.thread);
 
-        this.heapData.length = 0;
+                if(dst === undefined) {
+                    dst = [];
+
+                    this.perThread.set(zd.thread, dst);
+                    this.onNewThread.invoke(zd.thread);
+                }
 
-        for(const pd of safeData.plots) {
-            if(pd.name === 0) {
-                this.heapData.push(new HeapInfo(pd));
+                dst.push(new ZoneInfo(zd));
             }
+
+            //Without `plots_cursor`, the server sends the plots from the beginning again
+            if(wantPlots) {
+                for(const pd of safeData.plots) {
+                    if(pd.name === 0) {
+                        this.heapData.push(new HeapInfo(pd));
+                    }
+                }
+            }
+
+            const zonesDone = safeData.next_cursor === null;
+            wantPlots = safeData.plots_next_cursor !== null;
+
+            if(zonesDone && !wantPlots) {
+                break;
+            }
+
+            if(page + 1 >= MAX_PAGES) {
+                console.warn("Zone data between " + start + " and " + end + " is incomplete: " + safeData.truncated);
+                break;
+            }
+
+            cursors = (zonesDone ? "" : "&cursor=" + safeData.next_cursor) + (wantPlots ? "&plots_cursor=" + safeData.plots_next_cursor : "");
         }
 
         this.onMainDataChanged.invoke();
@@ -248,7 +312,7 @@ export class DataProvider {
         let data;
 
         try {
-            data = JSON.parse(await request("/data/frame-times/query-count?t=" + t + "&count=" + count));
+            data = JSON.parse(await request("/data/frame-times/query-count?t=" + toNanos(t) + "&count=" + count));
         } catch(err) {
             console.error(err);
             return false;
@@ -262,6 +326,10 @@ export class DataProvider {
         let safeData = data as FrameDataQueryResult;
         this.frameData.length = 0;
 
+        if(safeData.truncated !== null) {
+            console.warn("Only got " + safeData.results.length + " of " + count + " frames: " + safeData.truncated);
+        }
+
         for(const fd of safeData.results) {
             this.frameData.push(new FrameInfo(fd));
         }
@@ -269,29 +337,52 @@ export class DataProvider {
         if(this.frameData.length > 0 && this.frameData[0].start <= this.timeRange.min && this.frameData[this.frameData.length - 1].end >= this.timeRange.max) {
             this.useDetailedFrameData = false;
         } else {
-            try {
-                data = JSON.parse(await request("/data/frame-times/query-range?start=" + this.timeRange.min + "&end=" + this.timeRange.max));
-            } catch(err) {
-                console.error(err);
-                this.useDetailedFrameData = false;
-                this.onFrameDataChanged.invoke();
+            const range = this.timeRange;
+            const prefetch = prefetchHint(this.lastFrameRange, range.min, range.max);
+            const url = "/data/frame-times/query-range?start=" + toNanos(range.min) + "&end=" + toNanos(range.max) + prefetch;
+            let cursor = "";
+
+            this.lastFrameRange = { min: range.min, max: range.max };
+
+            for(let page = 0;; page++) {
+                try {
+                    data = JSON.parse(await request(url + cursor));
+                } catch(err) {
+                    console.error(err);
+                    this.useDetailedFrameData = false;
+                    this.onFrameDataChanged.invoke();
+
+                    return true;
+                }
 
-                return true;
-            }
-    
-            if(data.status !== "ok") {
-                console.error(data.error);
-                this.useDetailedFrameData = false;
-                this.onFrameDataChanged.invoke();
+                if(data.status !== "ok") {
+                    console.error(data.error);
+                    this.useDetailedFrameData = false;
+                    this.onFrameDataChanged.invoke();
 
-                return true;
-            }
-    
-            safeData = data as FrameDataQueryResult;
-            this.detailedFrameData.length = 0;
-    
-            for(const fd of safeData.results) {
-                this.detailedFrameData.push(new FrameInfo(fd));
+                    return true;
+                }
+
+                safeData = data as FrameDataQueryResult;
+
+                if(page === 0) {
+                    this.detailedFrameData.length = 0;
+                }
+
+                for(const fd of safeData.results) {
+                    this.detailedFrameData.push(new FrameInfo(fd));
+                }
+
+                if(safeData.next_cursor === undefined || safeData.next_cursor === null) {
+                    break;
+                }
+
+                if(page + 1 >= MAX_PAGES) {
+                    console.warn("Frame times between " + range.min + " and " + range.max + " are incomplete: " + safeData.truncated);
+                    break;
+                }
+
+                cursor = "&cursor=" + safeData.next_cursor;
             }
 
             this.useDetailedFrameData = true;
@@ -326,8 +417,10 @@ export class DataProvider {
             return;
         }
     
-        if(this.dataEnd !== data.end) {
-            this.dataEnd = data.end;
+        const end = toSeconds(data.end);
+
+        if(this.dataEnd !== end) {
+            this.dataEnd = end;
             this.onEndChanged.invoke();
         }
     }
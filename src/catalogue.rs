@@ -0,0 +1,146 @@
+use std::sync::{RwLock, Arc};
+
+use fxhash::FxHashMap;
+use serde::Serialize;
+
+///Statistics about a plot series, see `Catalogue`
+#[derive(Debug, Copy, Clone, Serialize)]
+pub struct PlotSeries
+{
+    pub color: u32,
+    pub count: u64,
+    pub min  : f64,
+    pub max  : f64,
+    pub first: u64,
+    pub last : u64
+}
+
+///Statistics about a thread, see `Catalogue`
+#[derive(Debug, Copy, Clone, Serialize)]
+pub struct ThreadInfo
+{
+    pub first: u64,
+    pub last : u64,
+    pub zones: u64
+}
+
+pub struct Accessor<V>(Arc<RwLock<FxHashMap<usize, V>>>);
+pub struct Catalogue<V>(Arc<RwLock<FxHashMap<usize, V>>>);
+
+///A `Catalogue` keeps track of statistics about every item
+///(such as plot series or threads) the server has received
+///so far, indexed by the key of their name (see
+///`string_collection::Key`).
+///
+///Just like `StringCollection`, it is only updated from
+///the poller thread and read through `Accessor`s.
+impl<V: Copy> Catalogue<V>
+{
+    pub fn new() -> Self {
+        Self(Arc::new(RwLock::new(Default::default())))
+    }
+
+    #[inline]
+    pub fn new_accessor(&self) -> Accessor<V> {
+        Accessor(self.0.clone())
+    }
+
+    ///Calls `update` on the statistics of `key`, or inserts the result of `new`
+    ///if `key` has never been seen before.
+    pub fn record<New: FnOnce() -> V, Upd: FnOnce(&mut V)>(&mut self, key: usize, new: New, update: Upd) {
+        self.0.write().unwrap().entry(key).and_modify(update).or_insert_with(new);
+    }
+
+    ///Only keeps the items for which `keep` returns true, and returns the keys
+    ///of those that were removed. `keep` may also update the items it keeps.
+    pub fn retain<Keep: FnMut(usize, &mut V) -> bool>(&mut self, mut keep: Keep) -> Vec<usize> {
+        let mut removed = Vec::new();
+
+        self.0.write().unwrap().retain(|&k, v| {
+            if keep(k, v) {
+                true
+            } else {
+                removed.push(k);
+                false
+            }
+        });
+
+        removed
+    }
+}
+
+impl PlotSeries {
+    pub fn new(time: u64, color: u32, value: f64) -> Self {
+        Self {
+            color,
+            count: 1,
+            min: value,
+            max: value,
+            first: time,
+            last: time
+        }
+    }
+
+    ///Samples may arrive out of order (see `MemDB::set_reorder_window()`),
+    ///the color is that of the latest one
+    pub fn add(&mut self, time: u64, color: u32, value: f64) {
+        self.count += 1;
+
+        if time >= self.last {
+            self.last = time;
+            self.color = color;
+        }
+
+        if time < self.first {
+            self.first = time;
+        }
+
+        if value < self.min {
+            self.min = value;
+        }
+
+        if value > self.max {
+            self.max = value;
+        }
+    }
+}
+
+impl ThreadInfo {
+    pub fn new(start: u64, end: u64) -> Self {
+        Self {
+            first: start,
+            last: end,
+            zones: 1
+        }
+    }
+
+    pub fn add(&mut self, start: u64, end: u64) {
+        self.merge(&Self::new(start, end));
+    }
+
+    pub fn merge(&mut self, other: &ThreadInfo) {
+        self.zones += other.zones;
+
+        if other.first < self.first {
+            self.first = other.first;
+        }
+
+        if other.last > self.last {
+            self.last = other.last;
+        }
+    }
+}
+
+impl<V: Copy> Accessor<V> {
+    ///Returns a copy of the whole catalogue, as a list of
+    ///(key, statistics) tuples.
+    pub fn list(&self) -> Vec<(usize, V)> {
+        self.0.read().unwrap().iter().map(|(&k, &v)| (k, v)).collect()
+    }
+}
+
+impl<V> Clone for Accessor<V> {
+    fn clone(&self) -> Self {
+        Accessor(self.0.clone())
+    }
+}
@@ -1,24 +1,70 @@
 
+use crate::memdb::{TimeData, TimeKey, Summarize, FixedLayout, Indexed, Columnar, ColumnView, ShouldStopQuery};
+
 use serde::{Serialize, Deserialize};
 use temporal_lens::shmem;
+use fxhash::FxHashMap;
+use std::mem::size_of;
+
+///Converts a time of the shared memory (seconds since the beginning of the
+///profiled program) into nanoseconds, the timeline of every MemDB and of the
+///REST API. Negative times are clamped to zero.
+#[inline]
+pub fn to_nanos(t: shmem::Time) -> u64 {
+    if t > 0.0 {
+        (t * 1e9).round() as u64
+    } else {
+        0
+    }
+}
+
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+#[repr(C)]
+pub struct LiteFrameData
+{
+    pub number  : u64,
+    pub duration: shmem::Duration
+}
 
+//Fields are ordered by size so that there is no padding, see `FixedLayout`
 #[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+#[repr(C)]
 pub struct LiteZoneData
 {
     pub uid     : usize,
-    pub color   : shmem::Color,
     pub duration: shmem::Duration,
-    pub depth   : u32,
     pub name    : usize,
-    pub thread  : usize
+    pub thread  : usize,
+    pub color   : shmem::Color,
+    pub depth   : u32
 }
 
 #[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+#[repr(C)]
 pub struct LitePlotData
 {
-    pub color: u32,
     pub value: f64,
-    pub name : usize
+    pub name : usize,
+    pub color: u32,
+
+    #[serde(skip)]
+    _pad: u32 //Always zero, see `FixedLayout`
+}
+
+///Keys zones are indexed by, see `memdb::Indexed`
+#[derive(Debug, Copy, Clone, Hash, Eq, PartialEq)]
+pub enum ZoneKey
+{
+    Name(usize),
+    Thread(usize)
+}
+
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+pub struct ReconstructedFrameData
+{
+    pub number  : u64,
+    pub end     : u64,
+    pub duration: shmem::Duration
 }
 
 #[derive(Debug, Copy, Clone, Serialize, Deserialize)]
@@ -27,7 +73,7 @@ pub struct ReconstructedZoneData
     pub entry_id: u64,
     pub zone_uid: usize,
     pub color   : shmem::Color,
-    pub end     : f64,
+    pub end     : u64,
     pub duration: shmem::Duration,
     pub depth   : u32,
     pub name    : usize,
@@ -37,14 +83,131 @@ pub struct ReconstructedZoneData
 #[derive(Debug, Copy, Clone, Serialize, Deserialize)]
 pub struct ReconstructedPlotData
 {
-    pub time : f64,
+    pub time : u64,
     pub color: u32,
     pub value: f64,
     pub name : usize
 }
 
+///Min, max, mean and last value of a plot series over some time range.
+#[derive(Debug, Copy, Clone)]
+pub struct PlotBucket
+{
+    pub count     : u64,
+    pub min       : f64,
+    pub max       : f64,
+    pub sum       : f64,
+    pub last      : f64,
+    pub first_time: u64,
+    pub last_time : u64
+}
+
+///Per-series `PlotBucket`s, indexed by plot name
+pub type PlotSummary = FxHashMap<usize, PlotBucket>;
+
+impl PlotBucket {
+    pub fn new(time: u64, value: f64) -> Self {
+        Self {
+            count: 1,
+            min: value,
+            max: value,
+            sum: value,
+            last: value,
+            first_time: time,
+            last_time: time
+        }
+    }
+
+    pub fn add(&mut self, time: u64, value: f64) {
+        self.merge(&Self::new(time, value));
+    }
+
+    pub fn merge(&mut self, other: &PlotBucket) {
+        self.count += other.count;
+        self.sum   += other.sum;
+
+        if other.min < self.min {
+            self.min = other.min;
+        }
+
+        if other.max > self.max {
+            self.max = other.max;
+        }
+
+        if other.first_time < self.first_time {
+            self.first_time = other.first_time;
+        }
+
+        if other.last_time >= self.last_time {
+            self.last = other.last;
+            self.last_time = other.last_time;
+        }
+    }
+
+    #[inline]
+    pub fn mean(&self) -> f64 {
+        self.sum / (self.count as f64)
+    }
+}
+
+///A group of zones of the same thread and depth, merged together. Used
+///for level-of-detail queries, where drawing every single zone would
+///be pointless.
+#[derive(Debug, Copy, Clone, Serialize)]
+pub struct BusySpan
+{
+    pub start: u64,
+    pub end  : u64,
+    pub count: u64,
+    pub busy : shmem::Duration
+}
+
+///Summary of a block of zones: every zone that isn't longer than the
+///block itself is merged into a `BusySpan`, indexed by (thread, depth).
+///The others are kept as-is in `long`, along with their offset in the
+///block.
+pub struct ZoneSummary
+{
+    pub spans: FxHashMap<(usize, u32), BusySpan>,
+    pub long : Vec<(usize, TimeData<LiteZoneData>)>
+}
+
+impl BusySpan {
+    pub fn new(end: u64, duration: shmem::Duration) -> Self {
+        Self {
+            start: end.saturating_sub(duration),
+            end,
+            count: 1,
+            busy: duration
+        }
+    }
+
+    pub fn merge(&mut self, other: &BusySpan) {
+        self.count += other.count;
+        self.busy  += other.busy;
+
+        if other.start < self.start {
+            self.start = other.start;
+        }
+
+        if other.end > self.end {
+            self.end = other.end;
+        }
+    }
+}
+
+impl LiteFrameData {
+    pub fn reconstruct(&self, end: u64) -> ReconstructedFrameData {
+        ReconstructedFrameData {
+            number: self.number,
+            end,
+            duration: self.duration
+        }
+    }
+}
+
 impl LiteZoneData {
-    pub fn reconstruct(&self, end: f64, entry_id: u64) -> ReconstructedZoneData {
+    pub fn reconstruct(&self, end: u64, entry_id: u64) -> ReconstructedZoneData {
         ReconstructedZoneData {
             entry_id,
             zone_uid: self.uid,
@@ -58,8 +221,27 @@ impl LiteZoneData {
     }
 }
 
+impl LiteZoneData {
+    ///Columns of zones in chunk files, see `memdb::Columnar`
+    pub const DURATION_COLUMN: usize = 0;
+    pub const NAME_COLUMN    : usize = 1;
+    pub const THREAD_COLUMN  : usize = 2;
+    pub const UID_COLUMN     : usize = 3;
+    pub const DEPTH_COLUMN   : usize = 4; //Narrow columns last, so that only they may need padding
+    pub const COLOR_COLUMN   : usize = 5;
+}
+
 impl LitePlotData {
-    pub fn reconstruct(&self, time: f64) -> ReconstructedPlotData {
+    pub fn new(color: u32, value: f64, name: usize) -> Self {
+        Self {
+            value,
+            name,
+            color,
+            _pad: 0
+        }
+    }
+
+    pub fn reconstruct(&self, time: u64) -> ReconstructedPlotData {
         ReconstructedPlotData {
             time,
             color: self.color,
@@ -69,14 +251,161 @@ impl LitePlotData {
     }
 }
 
-impl shmem::ShouldStopQuery for LiteZoneData {
-    fn should_stop_query(&self, t: f64, query_max: f64) -> bool {
-        self.depth == 0 && t - (self.duration as f64) * 1e-9 > query_max
+impl ShouldStopQuery for LiteFrameData {
+    fn should_stop_query(&self, t: u64, query_max: u64) -> bool {
+        t > query_max
+    }
+}
+
+impl ShouldStopQuery for LiteZoneData {
+    fn should_stop_query(&self, t: u64, query_max: u64) -> bool {
+        self.depth == 0 && t.saturating_sub(self.duration) > query_max
     }
 }
 
-impl shmem::ShouldStopQuery for LitePlotData {
-    fn should_stop_query(&self, t: f64, query_max: f64) -> bool {
+impl ShouldStopQuery for LitePlotData {
+    fn should_stop_query(&self, t: u64, query_max: u64) -> bool {
         t > query_max
     }
 }
+
+//Only integers and floats, and no padding (these fail to compile otherwise)
+unsafe impl FixedLayout for LiteFrameData {
+    const TYPE_ID: u32 = 0x4D52_4654; //"TFRM"
+}
+
+unsafe impl FixedLayout for LiteZoneData {
+    const TYPE_ID: u32 = 0x4E4F_5A54; //"TZON"
+}
+
+unsafe impl FixedLayout for LitePlotData {
+    const TYPE_ID: u32 = 0x544C_5054; //"TPLT"
+}
+
+const _: [(); 0] = [(); (size_of::<LiteFrameData>() != size_of::<u64>() + size_of::<shmem::Duration>()) as usize];
+const _: [(); 0] = [(); (size_of::<LiteZoneData>() != 3 * size_of::<usize>() + size_of::<shmem::Duration>() + size_of::<shmem::Color>() + size_of::<u32>()) as usize];
+const _: [(); 0] = [(); (size_of::<LitePlotData>() != size_of::<f64>() + size_of::<usize>() + 2 * size_of::<u32>()) as usize];
+
+impl Summarize for LiteZoneData {
+    type Summary = ZoneSummary;
+
+    fn summarize(data: &[TimeData<Self>]) -> ZoneSummary {
+        let block_span = match (data.first(), data.last()) {
+            (Some(first), Some(last)) => last.time - first.time,
+            _                         => 0 //Dropped chunk
+        };
+
+        let mut ret = ZoneSummary {
+            spans: Default::default(),
+            long : Vec::new()
+        };
+
+        for (i, entry) in data.iter().enumerate() {
+            if entry.data.duration > block_span {
+                ret.long.push((i, *entry));
+            } else {
+                let span = BusySpan::new(entry.time, entry.data.duration);
+
+                ret.spans.entry((entry.data.thread, entry.data.depth))
+                    .and_modify(|dst| dst.merge(&span))
+                    .or_insert(span);
+            }
+        }
+
+        ret
+    }
+}
+
+impl Summarize for LitePlotData {
+    type Summary = PlotSummary;
+
+    fn summarize(data: &[TimeData<Self>]) -> PlotSummary {
+        let mut ret = PlotSummary::default();
+
+        for entry in data {
+            ret.entry(entry.data.name)
+                .and_modify(|bucket| bucket.add(entry.time, entry.data.value))
+                .or_insert_with(|| PlotBucket::new(entry.time, entry.data.value));
+        }
+
+        ret
+    }
+}
+
+impl Summarize for LiteFrameData {
+    type Summary = ();
+
+    fn summarize(_: &[TimeData<Self>]) {}
+}
+
+impl Indexed for LiteZoneData {
+    type Key = ZoneKey;
+
+    fn index_keys<F: FnMut(ZoneKey)>(&self, mut f: F) {
+        f(ZoneKey::Name(self.name));
+        f(ZoneKey::Thread(self.thread));
+    }
+}
+
+impl Indexed for LitePlotData {
+    type Key = usize;
+
+    fn index_keys<F: FnMut(usize)>(&self, mut f: F) {
+        f(self.name);
+    }
+}
+
+impl Indexed for LiteFrameData {
+    type Key = ();
+
+    fn index_keys<F: FnMut(())>(&self, _: F) {}
+}
+
+impl Columnar for LiteZoneData {
+    const COLUMNS: &'static [usize] = &[
+        std::mem::size_of::<shmem::Duration>(),
+        std::mem::size_of::<usize>(),
+        std::mem::size_of::<usize>(),
+        std::mem::size_of::<usize>(),
+        std::mem::size_of::<u32>(),
+        std::mem::size_of::<shmem::Color>()
+    ];
+
+    fn write_column<K>(data: &[TimeData<Self, K>], column: usize, out: &mut Vec<u8>) {
+        for entry in data {
+            match column {
+                Self::DURATION_COLUMN => out.extend_from_slice(&entry.data.duration.to_ne_bytes()),
+                Self::NAME_COLUMN     => out.extend_from_slice(&entry.data.name.to_ne_bytes()),
+                Self::THREAD_COLUMN   => out.extend_from_slice(&entry.data.thread.to_ne_bytes()),
+                Self::DEPTH_COLUMN    => out.extend_from_slice(&entry.data.depth.to_ne_bytes()),
+                Self::UID_COLUMN      => out.extend_from_slice(&entry.data.uid.to_ne_bytes()),
+                Self::COLOR_COLUMN    => out.extend_from_slice(&entry.data.color.to_ne_bytes()),
+                _                     => unreachable!()
+            }
+        }
+    }
+
+    fn read_rows<K: TimeKey>(view: &ColumnView<K>, out: &mut Vec<TimeData<Self, K>>) {
+        let durations: &[shmem::Duration] = view.column(Self::DURATION_COLUMN);
+        let names: &[usize] = view.column(Self::NAME_COLUMN);
+        let threads: &[usize] = view.column(Self::THREAD_COLUMN);
+        let depths: &[u32] = view.column(Self::DEPTH_COLUMN);
+        let uids: &[usize] = view.column(Self::UID_COLUMN);
+        let colors: &[shmem::Color] = view.column(Self::COLOR_COLUMN);
+
+        out.extend(view.times().iter().enumerate().map(|(i, &time)| TimeData {
+            time,
+            data: LiteZoneData {
+                uid     : uids[i],
+                duration: durations[i],
+                name    : names[i],
+                thread  : threads[i],
+                color   : colors[i],
+                depth   : depths[i]
+            }
+        }));
+    }
+}
+
+impl Columnar for LitePlotData {}
+impl Columnar for LiteFrameData {}
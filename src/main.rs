@@ -7,16 +7,21 @@
 mod stoppable_thread;
 mod shmem_poller;
 mod string_collection;
+mod catalogue;
+mod zone_filter;
 mod memdb;
 mod common;
 
-use temporal_lens::shmem::{SharedMemory, FrameData};
+use temporal_lens::shmem::SharedMemory;
 use string_collection::{StringCollection, Accessor as SCAccessor, Key as SCKey};
-use memdb::{MemDB, Accessor as MDBAccessor};
-use common::{LiteZoneData, LitePlotData};
+use catalogue::{Catalogue, PlotSeries, ThreadInfo, Accessor as CatalogueAccessor};
+use zone_filter::{ZoneFilterParams, ZoneFilter};
+use memdb::{MemDB, MemDbContext, Accessor as MDBAccessor, TimeData, BucketItem, Summarize, FixedLayout, Indexed, Columnar, Budget, BudgetLimits, PrefetchHint};
+use common::{LiteFrameData, LiteZoneData, LitePlotData, ReconstructedFrameData, ReconstructedZoneData, PlotBucket, PlotSummary, BusySpan, ZoneKey, to_nanos};
 
 use std::path::PathBuf;
-use std::time::Instant;
+use std::time::{Instant, Duration, SystemTime, UNIX_EPOCH};
+use std::str::FromStr;
 
 use rocket::{get, routes, State, Outcome};
 use rocket::config::{Config as RocketConfig, Environment as RocketEnv};
@@ -26,10 +31,11 @@ use rocket_contrib::{json, json::JsonValue, serve::StaticFiles};
 
 use log::{info, error, debug, warn};
 use clap::{App, Arg};
-use fxhash::FxHashMap;
+use fxhash::{FxHashMap, FxHashSet};
 
 const TEMPORAL_LENS_VERSION: u32 = 0x00_01_0000;
-const REST_PROTCOL_VERSION: u32 = 0x00_01_0000; //TODO: Change protocols version to simple numbers!!
+const REST_PROTCOL_VERSION: u32 = 0x00_02_0000; //TODO: Change protocols version to simple numbers!!
+const MAX_BUCKETS: usize = 4096; //Per bucket query, each bucket being allocated up front
 
 fn version_string(version: u32) -> String {
     let major = (version & 0xFF_00_0000) >> 24;
@@ -48,10 +54,13 @@ fn shutdown() {
 }
 
 struct Managed {
-    frame_db: MDBAccessor<FrameData>,
+    frame_db: MDBAccessor<LiteFrameData>,
     zone_db: MDBAccessor<LiteZoneData>,
     plot_db: MDBAccessor<LitePlotData>,
     str_collection: SCAccessor,
+    plot_catalogue: CatalogueAccessor<PlotSeries>,
+    thread_catalogue: CatalogueAccessor<ThreadInfo>,
+    limits: BudgetLimits, //Of each query
     start: Instant
 }
 
@@ -62,15 +71,45 @@ fn index() -> Redirect {
 
 #[get("/info")]
 fn info_endpoint(state: State<Managed>) -> JsonValue {
-    let (loaded, total) = state.zone_db.get_stats();
-    let state = format!("{} chunks out of {} loaded", loaded, total);
+    let (in_ram, total) = state.zone_db.get_stats();
+    let strings = state.str_collection.get_stats();
+    let zones = state.zone_db.get_entry_count();
+    let earliest = state.zone_db.get_min_time();
+    let late = json!({
+        "frames": state.frame_db.get_late_count(),
+        "zones": state.zone_db.get_late_count(),
+        "plots": state.plot_db.get_late_count()
+    });
+
+    let state = format!("{} chunks out of {} held in RAM", in_ram, total);
 
     json!({
         "motd": "Welcome to the Temporal Lens Server!",
         "version": version_string(TEMPORAL_LENS_VERSION),
         "lib-protocol-version": version_string(temporal_lens::shmem::PROTOCOL_VERSION),
         "rest-protocol-version": version_string(REST_PROTCOL_VERSION),
-        "state": state
+        "state": state,
+        "zones": zones,
+        "earliest": earliest,
+        "late": late,
+        "strings": strings
+    })
+}
+
+#[get("/health")]
+fn health_endpoint(state: State<Managed>) -> JsonValue {
+    let frames = state.frame_db.get_health();
+    let zones = state.zone_db.get_health();
+    let plots = state.plot_db.get_health();
+
+    json!({
+        "status": "ok",
+        "healthy": frames.is_empty() && zones.is_empty() && plots.is_empty(),
+        "failed_chunks": {
+            "frames": frames,
+            "zones": zones,
+            "plots": plots
+        }
     })
 }
 
@@ -96,13 +135,6 @@ fn shutdown_endpoint() -> JsonValue {
 
 macro_rules! validate_start_end {
     ($start:ident, $end:ident) => {
-        if $start < 0.0 {
-            return json!({
-                "status": "error",
-                "error": "query with negative start and specified end are not supported"
-            });
-        }
-    
         if $start > $end {
             return json!({
                 "status": "error",
@@ -112,102 +144,705 @@ macro_rules! validate_start_end {
     };
 }
 
-#[get("/data/frame-times/query-range?<start>&<end>")]
-fn query_frame_times_range(start: f64, end: Option<f64>, state: State<Managed>) -> JsonValue {
+///Creates an iterator over `$db` that either starts at `$start` or resumes from `$cursor`
+macro_rules! range_iter {
+    ($db:expr, $start:expr, $end:expr, $cursor:expr) => {
+        match $cursor {
+            Some(cursor) => $db.iter_from(cursor, $end),
+            None         => $db.iter($start, $end)
+        }
+    };
+}
+
+///Parses the `prefetch` hint of range endpoints (see `memdb::Accessor::prefetch()`)
+macro_rules! parse_prefetch_hint {
+    ($prefetch:ident) => {
+        match $prefetch.as_ref().map(|s| s.as_str()) {
+            None          => None,
+            Some("left")  => Some(PrefetchHint::Left),
+            Some("right") => Some(PrefetchHint::Right),
+            Some(_)       => return json!({
+                "status": "error",
+                "error": "prefetch must be either left or right"
+            })
+        }
+    };
+}
+
+///A negative `start` without `end` queries the last `-start` nanoseconds
+#[get("/data/frame-times/query-range?<start>&<end>&<limit>&<cursor>&<prefetch>")]
+fn query_frame_times_range(start: i64, end: Option<u64>, limit: Option<usize>, cursor: Option<u64>, prefetch: Option<String>, state: State<Managed>) -> JsonValue {
+    let hint = parse_prefetch_hint!(prefetch);
+
+    let start = if start >= 0 {
+        start as u64
+    } else if end.is_none() {
+        state.frame_db.get_max_time().saturating_sub((start as u64).wrapping_neg()) //i.e. -start
+    } else {
+        return json!({
+            "status": "error",
+            "error": "query with negative start and specified end are not supported"
+        });
+    };
+
     if let Some(actual_end) = end {
         validate_start_end!(start, actual_end);
+
+        state.frame_db.prefetch(start, actual_end, hint);
     }
 
+    let budget = Budget::new(state.limits);
+    let mut iter = range_iter!(state.frame_db, start, end, cursor).with_budget(&budget);
+    let results: Vec<ReconstructedFrameData> = iter.by_ref().take(limit.unwrap_or(usize::MAX)).map(|(_, r)| r.data.reconstruct(r.time)).collect();
+
+    json!({
+        "status": "ok",
+        "results": results,
+        "next_cursor": iter.next_cursor(),
+        "truncated": budget.exceeded()
+    })
+}
+
+///Returns `count` frames around `t`. If the query budget is exceeded, `truncated`
+///tells why and only the frames found until then are returned.
+#[get("/data/frame-times/query-count?<t>&<count>")]
+fn query_frame_times_count(t: u64, count: usize, state: State<Managed>) -> JsonValue {
     let mut results = Vec::new();
-    state.frame_db.query(start, end, |_, r| results.push(r.data));
+    let budget = Budget::new(state.limits);
+    state.frame_db.query_count(t, count, Some(&budget), |r| results.push(r.data.reconstruct(r.time)));
 
     json!({
         "status": "ok",
-        "results": results
+        "results": results,
+        "truncated": budget.exceeded()
     })
 }
 
+macro_rules! validate_buckets {
+    ($start:ident, $end:ident, $buckets:ident) => {
+        if let Some(n) = $buckets {
+            if n == 0 || $start >= $end {
+                return json!({
+                    "status": "error",
+                    "error": "bucket queries require at least one bucket and start < end"
+                });
+            }
 
-#[get("/data/frame-times/query-count?<t>&<count>")]
-fn query_frame_times_count(t: f64, count: usize, state: State<Managed>) -> JsonValue {
-    if t < 0.0 {
-        return json!({
-            "status": "error",
-            "error": "query with negative start and specified end are not supported"
-        });
+            if n > MAX_BUCKETS {
+                return json!({
+                    "status": "error",
+                    "error": format!("bucket queries are limited to {} buckets", MAX_BUCKETS)
+                });
+            }
+        }
+    };
+}
+
+///Computes `buckets` plot buckets between `start` and `end` for each plot series,
+///or only for the series named `only` if specified. Buckets are only complete
+///before the returned time, if `budget` was exceeded (see `Accessor::query_buckets()`).
+fn query_plot_buckets(state: &Managed, start: u64, end: u64, buckets: usize, only: Option<usize>, budget: &Budget) -> (FxHashMap<usize, Vec<JsonValue>>, Option<u64>) {
+    let buckets = buckets as u64;
+    let span = end - start;
+    let width = span / buckets + if span % buckets == 0 { 0 } else { 1 }; //Rounded up (without overflowing), so that there are no more than `buckets` buckets
+    let mut per_series: FxHashMap<usize, Vec<JsonValue>> = Default::default();
+
+    let add = |dst: &mut PlotSummary, name: usize, src: &PlotBucket| {
+        if only.map(|n| n == name).unwrap_or(true) {
+            dst.entry(name).and_modify(|x| x.merge(src)).or_insert(*src);
+        }
+    };
+
+    let (aggregated, next_start) = state.plot_db.aggregate(start, end, width, Some(budget), |dst: &mut PlotSummary, item| match item {
+        BucketItem::Summary(_, summary) => {
+            for (&name, src) in summary {
+                add(dst, name, src);
+            }
+        },
+
+        BucketItem::Entry(_, r) => add(dst, r.data.name, &PlotBucket::new(r.time, r.data.value))
+    });
+
+    for (i, summary) in aggregated.into_iter().enumerate() {
+        for (name, b) in summary {
+            per_series.entry(name).or_insert_with(Vec::new).push(json!({
+                "start": start + (i as u64) * width,
+                "end": u64::min(start + ((i + 1) as u64) * width, end),
+                "count": b.count,
+                "min": b.min,
+                "max": b.max,
+                "mean": b.mean(),
+                "last": b.last
+            }));
+        }
     }
 
-    let mut results = Vec::new();
-    state.frame_db.query_count(t, count, |r| results.push(r.data));
+    (per_series, next_start)
+}
+
+///Merges zones shorter than `resolution` into busy spans (see `query_zones_lod()`)
+fn lod_add_zone(zones: &mut Vec<ReconstructedZoneData>, busy: &mut FxHashMap<(usize, u32, usize), BusySpan>, bucket: usize, k: u64, r: &TimeData<LiteZoneData>, resolution: u64, filter: &ZoneFilter) {
+    if !filter.matches(&r.data) {
+        return;
+    }
+
+    if r.data.duration >= resolution {
+        zones.push(r.data.reconstruct(r.time, k));
+    } else {
+        let span = BusySpan::new(r.time, r.data.duration);
+
+        busy.entry((r.data.thread, r.data.depth, bucket))
+            .and_modify(|dst| dst.merge(&span))
+            .or_insert(span);
+    }
+}
+
+///Lists the zones that match `filter`, through the `zone_db` index if it restricts names
+///or threads. Also returns the cursor to resume from, if `limit` or `budget` was reached.
+fn query_zones(state: &Managed, start: u64, end: u64, limit: Option<usize>, cursor: Option<u64>, filter: &ZoneFilter, budget: &Budget) -> (Vec<ReconstructedZoneData>, Option<u64>) {
+    let mut iter = range_iter!(state.zone_db, start, Some(end), cursor).with_budget(budget);
+
+    if let Some(keys) = filter.index_keys() {
+        iter = iter.with_keys(keys);
+    }
+
+    let zones = iter.by_ref()
+        .filter(|(_, r)| filter.matches(&r.data))
+        .take(limit.unwrap_or(usize::MAX))
+        .map(|(k, r)| r.data.reconstruct(r.time, k))
+        .collect();
+
+    (zones, iter.next_cursor())
+}
+
+///Level-of-detail zone query. Zones that are longer than `resolution` are returned
+///as-is, the others are merged into one `BusySpan` per thread, depth and bucket of
+///`resolution` nanoseconds. Relies on the summaries of `zone_db` so that chunks don't
+///have to be read from disk, except when filtering by zone name: summaries don't
+///keep track of names, so the `zone_db` index is used instead.
+///
+///If `budget` is exceeded, also returns where results are incomplete from: a time
+///to pass as `start`, or when filtering by zone name, a cursor to pass as `cursor`
+///along with the same `start`.
+fn query_zones_lod(state: &Managed, start: u64, end: u64, resolution: u64, cursor: Option<u64>, filter: &ZoneFilter, budget: &Budget) -> (Vec<ReconstructedZoneData>, Vec<(usize, u32, BusySpan)>, Option<u64>, Option<u64>) {
+    let mut zones = Vec::new();
+    let mut busy: FxHashMap<(usize, u32, usize), BusySpan> = Default::default();
+    let last_bucket = ((end - start) / resolution) as usize;
+    let mut next_start = None;
+    let mut next_cursor = None;
+
+    match filter.index_keys() {
+        Some(keys) if filter.has_names() => {
+            let mut iter = range_iter!(state.zone_db, start, Some(end), cursor).with_keys(keys).with_budget(budget);
+            let entries: Vec<_> = iter.by_ref().collect();
+
+            //Zones may share their end time, so only a cursor is sure to make progress
+            if iter.truncated() {
+                next_cursor = iter.next_cursor();
+            }
+
+            for (k, r) in entries {
+                let bucket = usize::min((r.time.saturating_sub(start) / resolution) as usize, last_bucket);
+                lod_add_zone(&mut zones, &mut busy, bucket, k, &r, resolution, filter);
+            }
+        },
+
+        _ => {
+            next_start = state.zone_db.query_buckets(start, end, resolution, Some(budget), |bucket, item| match item {
+                BucketItem::Summary(k_base, summary) => {
+                    for (&(thread, depth), span) in &summary.spans {
+                        if !filter.matches_span(thread, depth) {
+                            continue;
+                        }
+
+                        busy.entry((thread, depth, bucket))
+                            .and_modify(|dst| dst.merge(span))
+                            .or_insert(*span);
+                    }
+
+                    for (offset, r) in &summary.long {
+                        lod_add_zone(&mut zones, &mut busy, bucket, k_base + (*offset as u64), r, resolution, filter);
+                    }
+                },
+
+                BucketItem::Entry(k, r) => lod_add_zone(&mut zones, &mut busy, bucket, k, r, resolution, filter)
+            });
+
+            //Zones ending after `end` might have started before it. If the budget runs out
+            //here, some of them are missing, which only `truncated` tells the client about.
+            if next_start.is_none() {
+                for (k, r) in state.zone_db.iter(end, Some(end)).with_budget(budget) {
+                    if r.time > end {
+                        lod_add_zone(&mut zones, &mut busy, last_bucket, k, &r, resolution, filter);
+                    }
+                }
+            }
+        }
+    }
+
+    zones.sort_by_key(|z| z.entry_id);
+
+    let mut busy: Vec<(usize, u32, BusySpan)> = busy.into_iter().map(|((thread, depth, _), span)| (thread, depth, span)).collect();
+    busy.sort_by_key(|&(thread, depth, span)| (thread, depth, span.start));
+
+    (zones, busy, next_start, next_cursor)
+}
+
+///Calls `callback` with the last sample before `start` of each plot series, so that
+///every plot can be drawn from `start`. Returns the cursors of the series whose sample
+///wasn't found within `budget`, see `Accessor::query_previous_matching_from()`.
+fn query_previous_samples<Func: FnMut(&TimeData<LitePlotData>)>(state: &Managed, start: u64, budget: &Budget, mut callback: Func) -> FxHashMap<usize, u64> {
+    let mut cursors = FxHashMap::default();
+
+    for (name, series) in state.plot_catalogue.list() {
+        if series.first >= start {
+            continue;
+        }
+
+        //No need to look past the last sample of the series
+        let before = u64::min(start, series.last.saturating_add(1));
+
+        if let Some(cursor) = state.plot_db.query_previous_matching(before, series.first, &[name], Some(budget), &mut callback) {
+            cursors.insert(name, cursor);
+        }
+    }
+
+    cursors
+}
+
+///Strings and thread names that changed over time (see `StringCollection`) can't be
+///described by a simple key -> name map. This lists all their versions.
+fn query_string_versions(state: &Managed, strings: &FxHashMap<usize, &str>, thread_names: &FxHashMap<usize, &str>) -> JsonValue {
+    let mut string_versions = FxHashMap::default();
+    let mut thread_name_versions = FxHashMap::default();
+
+    for &key in strings.keys() {
+        let versions = state.str_collection.get_versions(SCKey::StaticString(key));
+
+        if versions.len() > 1 {
+            string_versions.insert(key, versions);
+        }
+    }
+
+    for &key in thread_names.keys() {
+        let versions = state.str_collection.get_versions(SCKey::ThreadName(key));
+
+        if versions.len() > 1 {
+            thread_name_versions.insert(key, versions);
+        }
+    }
 
     json!({
-        "status": "ok",
-        "results": results
+        "strings": string_versions,
+        "thread_names": thread_name_versions
     })
 }
 
-#[get("/data/plots?<start>&<end>")]
-fn query_plots_endpoint(start: f64, end: f64, state: State<Managed>) -> JsonValue {
+macro_rules! resolve_zone_filter {
+    ($params:ident, $state:ident) => {
+        match $params {
+            Ok(params) => params.resolve(&$state.str_collection, &$state.thread_catalogue),
+            Err(err)   => return json!({
+                "status": "error",
+                "error": err
+            })
+        }
+    };
+}
+
+///`limit` and `cursor` only apply to zones. `limit` is ignored if `resolution` is specified,
+///and so is `cursor` unless zones are filtered by name (see below).
+///
+///If the query budget is exceeded, `truncated` tells why, and the query can be resumed
+///by passing `next_cursor` as `cursor` and `plots_next_cursor` as `plots_cursor`. When
+///`plots_cursor` is specified without `cursor`, zones are considered complete and skipped.
+///Busy spans and plot buckets are only complete before `lod_next_start` and
+///`buckets_next_start` respectively, which can be passed as `start` to get the rest.
+///The LOD pass restarts from `start` whenever `resolution` is specified, `plots_cursor`
+///or not, so pass `lod_next_start` as `start` along with `plots_cursor` to resume both.
+///When filtering by zone name, the LOD pass returns `next_cursor` instead, to pass as
+///`cursor` along with the same `start`.
+///Previous samples that weren't found in time are listed in `previous_cursors`, see
+///the `previous_cursor` parameter of `/data/plot-series/<name>`.
+#[get("/data/plots?<start>&<end>&<buckets>&<resolution>&<limit>&<cursor>&<plots_cursor>&<prefetch>&<filter..>")]
+fn query_plots_endpoint(start: u64, end: u64, buckets: Option<usize>, resolution: Option<u64>, limit: Option<usize>, cursor: Option<u64>, plots_cursor: Option<u64>, prefetch: Option<String>, filter: Result<ZoneFilterParams, String>, state: State<Managed>) -> JsonValue {
     validate_start_end!(start, end);
+    validate_buckets!(start, end, buckets);
+    let filter = resolve_zone_filter!(filter, state);
+    let hint = parse_prefetch_hint!(prefetch);
+
+    if resolution.map(|r| r == 0).unwrap_or(false) {
+        return json!({
+            "status": "error",
+            "error": "resolution must be positive"
+        });
+    }
 
     let mut strings: FxHashMap<usize, &str> = Default::default();
     let mut thread_names: FxHashMap<usize, &str> = Default::default();
-    let mut zones = Vec::new();
+    let zones;
+    let mut busy = Vec::new();
     let mut plots = Vec::new();
+    let mut next_cursor = None;
+    let mut lod_next_start = None;
+    let mut previous_cursors: FxHashMap<usize, u64> = Default::default();
+    let budget = Budget::new(state.limits);
 
-    state.zone_db.query(start, Some(end), |k, r| {
-        strings.entry(r.data.name).or_insert_with(|| state.str_collection.get(SCKey::StaticString(r.data.name)).unwrap_or("????"));
-        thread_names.entry(r.data.thread).or_insert_with(|| state.str_collection.get(SCKey::ThreadName(r.data.thread)).unwrap_or("????"));
+    state.zone_db.prefetch(start, end, hint);
+    state.plot_db.prefetch(start, end, hint);
 
-        zones.push(r.data.reconstruct(r.time, k));
-    });
+    if let Some(resolution) = resolution {
+        let (lod_zones, lod_busy, next_start, lod_next_cursor) = query_zones_lod(&state, start, end, resolution, cursor, &filter, &budget);
 
-    state.plot_db.query_previous(start, |r| {
-        if r.data.name != 0 {
-            strings.entry(r.data.name).or_insert_with(|| state.str_collection.get(SCKey::StaticString(r.data.name)).unwrap_or("????"));
+        zones = lod_zones;
+        lod_next_start = next_start;
+        next_cursor = lod_next_cursor;
+        busy = lod_busy.into_iter().map(|(thread, depth, span)| {
+            thread_names.entry(thread).or_insert_with(|| state.str_collection.get_at(SCKey::ThreadName(thread), span.start).unwrap_or("????"));
+
+            json!({
+                "thread": thread,
+                "depth": depth,
+                "start": span.start,
+                "end": span.end,
+                "count": span.count,
+                "busy": span.busy
+            })
+        }).collect();
+    } else if cursor.is_none() && plots_cursor.is_some() {
+        zones = Vec::new();
+    } else {
+        let (listed, listed_next_cursor) = query_zones(&state, start, end, limit, cursor, &filter, &budget);
+
+        zones = listed;
+        next_cursor = listed_next_cursor;
+    }
+
+    for z in &zones {
+        let zone_start = z.end.saturating_sub(z.duration);
+
+        strings.entry(z.name).or_insert_with(|| state.str_collection.get_at(SCKey::StaticString(z.name), zone_start).unwrap_or("????"));
+        thread_names.entry(z.thread).or_insert_with(|| state.str_collection.get_at(SCKey::ThreadName(z.thread), zone_start).unwrap_or("????"));
+    }
+
+    if plots_cursor.is_none() {
+        previous_cursors = query_previous_samples(&state, start, &budget, |r| {
+            if r.data.name != 0 {
+                strings.entry(r.data.name).or_insert_with(|| state.str_collection.get_at(SCKey::StaticString(r.data.name), r.time).unwrap_or("????"));
+            }
+
+            plots.push(r.data.reconstruct(r.time));
+        });
+    }
+
+    plots.sort_by_key(|p| p.time);
+
+    if let Some(n) = buckets {
+        let (plot_buckets, buckets_next_start) = query_plot_buckets(&state, start, end, n, None, &budget);
+
+        for &name in plot_buckets.keys() {
+            if name != 0 {
+                strings.entry(name).or_insert_with(|| state.str_collection.get_at(SCKey::StaticString(name), start).unwrap_or("????"));
+            }
         }
 
-        plots.push(r.data.reconstruct(r.time));
-    });
+        let string_versions = query_string_versions(&state, &strings, &thread_names);
 
-    state.plot_db.query(start, Some(end), |_, r| {
+        return json!({
+            "status": "ok",
+            "strings": strings,
+            "thread_names": thread_names,
+            "string_versions": string_versions,
+            "zones": zones,
+            "next_cursor": next_cursor,
+            "busy": busy,
+            "lod_next_start": lod_next_start,
+            "plots": plots,
+            "previous_cursors": previous_cursors,
+            "plot_buckets": plot_buckets,
+            "buckets_next_start": buckets_next_start,
+            "truncated": budget.exceeded()
+        });
+    }
+
+    let mut plot_iter = range_iter!(state.plot_db, start, Some(end), plots_cursor).with_budget(&budget);
+
+    for (_, r) in plot_iter.by_ref() {
         if r.data.name != 0 {
-            strings.entry(r.data.name).or_insert_with(|| state.str_collection.get(SCKey::StaticString(r.data.name)).unwrap_or("????"));
+            strings.entry(r.data.name).or_insert_with(|| state.str_collection.get_at(SCKey::StaticString(r.data.name), r.time).unwrap_or("????"));
         }
 
         plots.push(r.data.reconstruct(r.time));
-    });
+    }
 
-    let plots_slice = if plots.len() >= 2 && plots[0].time == plots[1].time {
-        error!("Previous plot query and regular plot query collision!");
-        &plots[1..]
-    } else {
-        &plots
-    };
+    let plots_next_cursor = if plot_iter.truncated() { plot_iter.next_cursor() } else { None };
+    let string_versions = query_string_versions(&state, &strings, &thread_names);
 
     json!({
         "status": "ok",
         "strings": strings,
         "thread_names": thread_names,
+        "string_versions": string_versions,
         "zones": zones,
-        "plots": plots_slice
+        "next_cursor": next_cursor,
+        "busy": busy,
+        "lod_next_start": lod_next_start,
+        "plots": plots,
+        "previous_cursors": previous_cursors,
+        "plots_next_cursor": plots_next_cursor,
+        "truncated": budget.exceeded()
+    })
+}
+
+///Number of zones, total and max duration per zone name over [start; end], sorted
+///by total duration. Only scans the columns it needs, see `memdb::Columnar`.
+///
+///If the query budget is exceeded, `truncated` tells why, and the stats only cover
+///[start; next_start[. Pass `next_start` as `start` to get the stats of the rest.
+#[get("/data/zone-stats?<start>&<end>&<filter..>")]
+fn query_zone_stats(start: u64, end: u64, filter: Result<ZoneFilterParams, String>, state: State<Managed>) -> JsonValue {
+    validate_start_end!(start, end);
+    let filter = resolve_zone_filter!(filter, state);
+
+    let mut stats: FxHashMap<usize, (u64, temporal_lens::shmem::Duration, temporal_lens::shmem::Duration)> = Default::default();
+    let budget = Budget::new(state.limits);
+
+    let next_start = state.zone_db.scan_columns(start, end, Some(&budget), |view| {
+        let durations: &[temporal_lens::shmem::Duration] = view.column(LiteZoneData::DURATION_COLUMN);
+        let names: &[usize] = view.column(LiteZoneData::NAME_COLUMN);
+        let threads: &[usize] = view.column(LiteZoneData::THREAD_COLUMN);
+        let depths: &[u32] = view.column(LiteZoneData::DEPTH_COLUMN);
+
+        for i in 0..durations.len() {
+            if !filter.matches_name(names[i]) || !filter.matches_span(threads[i], depths[i]) {
+                continue;
+            }
+
+            let entry = stats.entry(names[i]).or_insert((0, 0, 0));
+            entry.0 += 1;
+            entry.1 += durations[i];
+
+            if durations[i] > entry.2 {
+                entry.2 = durations[i];
+            }
+        }
+    });
+
+    let mut stats: Vec<_> = stats.into_iter().collect();
+    stats.sort_by(|a, b| (b.1).1.cmp(&(a.1).1));
+
+    let mut strings: FxHashMap<usize, &str> = Default::default();
+    let stats: Vec<JsonValue> = stats.into_iter().map(|(name, (count, total, max))| {
+        strings.entry(name).or_insert_with(|| state.str_collection.get_at(SCKey::StaticString(name), start).unwrap_or("????"));
+
+        json!({
+            "name": name,
+            "count": count,
+            "total": total,
+            "max": max
+        })
+    }).collect();
+
+    json!({
+        "status": "ok",
+        "strings": strings,
+        "stats": stats,
+        "next_start": next_start,
+        "truncated": budget.exceeded()
+    })
+}
+
+fn find_plot_series(state: &Managed, name: &str) -> Option<(usize, PlotSeries)> {
+    state.plot_catalogue.list().into_iter().find(|&(key, _)| state.str_collection.get(SCKey::StaticString(key)) == Some(name))
+}
+
+#[get("/data/plot-series")]
+fn query_plot_series_list(state: State<Managed>) -> JsonValue {
+    let series: Vec<JsonValue> = state.plot_catalogue.list().into_iter().map(|(key, s)| json!({
+        "key": key,
+        "name": state.str_collection.get(SCKey::StaticString(key)).unwrap_or("????"),
+        "color": s.color,
+        "count": s.count,
+        "min": s.min,
+        "max": s.max,
+        "first": s.first,
+        "last": s.last
+    })).collect();
+
+    json!({
+        "status": "ok",
+        "series": series
+    })
+}
+
+///`limit` and `cursor` are ignored if `buckets` is specified.
+///
+///If the query budget is exceeded, `truncated` tells why. Samples can then be resumed
+///by passing `next_cursor` as `cursor`, and buckets by passing `next_start` as `start`.
+///If the sample preceding `start` wasn't found in time, `previous_cursor` is returned:
+///pass it back (along with the same `start`) to resume the search.
+#[get("/data/plot-series/<name>?<start>&<end>&<buckets>&<limit>&<cursor>&<previous_cursor>&<prefetch>")]
+fn query_plot_series(name: String, start: u64, end: u64, buckets: Option<usize>, limit: Option<usize>, cursor: Option<u64>, previous_cursor: Option<u64>, prefetch: Option<String>, state: State<Managed>) -> JsonValue {
+    validate_start_end!(start, end);
+    validate_buckets!(start, end, buckets);
+    let hint = parse_prefetch_hint!(prefetch);
+
+    let (key, series) = match find_plot_series(&state, &name) {
+        Some(x) => x,
+        None    => return json!({
+            "status": "error",
+            "error": "no such plot series"
+        })
+    };
+
+    let mut samples = Vec::new();
+    let mut next_previous_cursor = None;
+    let budget = Budget::new(state.limits);
+
+    state.plot_db.prefetch(start, end, hint);
+
+    if let Some(previous_cursor) = previous_cursor {
+        next_previous_cursor = state.plot_db.query_previous_matching_from(previous_cursor, series.first, &[key], Some(&budget), |r| samples.push(r.data.reconstruct(r.time)));
+    } else if series.first < start && cursor.is_none() {
+        let before = u64::min(start, series.last.saturating_add(1)); //No need to look past the last sample of the series
+        next_previous_cursor = state.plot_db.query_previous_matching(before, series.first, &[key], Some(&budget), |r| samples.push(r.data.reconstruct(r.time)));
+    }
+
+    if let Some(n) = buckets {
+        let (mut per_series, next_start) = query_plot_buckets(&state, start, end, n, Some(key), &budget);
+        let list = per_series.remove(&key).unwrap_or_default();
+
+        return json!({
+            "status": "ok",
+            "key": key,
+            "name": name,
+            "color": series.color,
+            "count": series.count,
+            "min": series.min,
+            "max": series.max,
+            "samples": samples,
+            "previous_cursor": next_previous_cursor,
+            "buckets": list,
+            "next_start": next_start,
+            "truncated": budget.exceeded()
+        });
+    }
+
+    let mut iter = range_iter!(state.plot_db, start, Some(end), cursor).with_keys(vec![key]).with_budget(&budget);
+
+    samples.extend(iter.by_ref()
+        .take(limit.unwrap_or(usize::MAX))
+        .map(|(_, r)| r.data.reconstruct(r.time)));
+
+    json!({
+        "status": "ok",
+        "key": key,
+        "name": name,
+        "color": series.color,
+        "count": series.count,
+        "min": series.min,
+        "max": series.max,
+        "samples": samples,
+        "previous_cursor": next_previous_cursor,
+        "next_cursor": iter.next_cursor(),
+        "truncated": budget.exceeded()
+    })
+}
+
+#[get("/data/threads")]
+fn query_threads(state: State<Managed>) -> JsonValue {
+    list_threads(&state)
+}
+
+///Lists the threads of `state.thread_catalogue`, sorted by their first zone
+fn list_threads(state: &Managed) -> JsonValue {
+    let mut threads = state.thread_catalogue.list();
+    threads.sort_by_key(|t| t.1.first);
+
+    let threads: Vec<JsonValue> = threads.into_iter().map(|(key, t)| json!({
+        "key": key,
+        "name": state.str_collection.get(SCKey::ThreadName(key)).unwrap_or("????"),
+        "first": t.first,
+        "last": t.last,
+        "zones": t.zones
+    })).collect();
+
+    json!({
+        "status": "ok",
+        "threads": threads
+    })
+}
+
+///Lists the strings of `state.str_collection` for which `filter` returns true, sorted by name
+fn list_strings<Func: Fn(SCKey) -> Option<usize>>(state: &Managed, filter: Func) -> JsonValue {
+    let mut strings = Vec::new();
+
+    state.str_collection.for_each(|k, s| {
+        if let Some(key) = filter(k) {
+            strings.push((key, s));
+        }
+    });
+
+    strings.sort_by(|a, b| a.1.cmp(b.1));
+    let strings: Vec<JsonValue> = strings.into_iter().map(|(key, s)| json!({ "key": key, "name": s })).collect();
+
+    json!({
+        "status": "ok",
+        "strings": strings
+    })
+}
+
+///Names of the zones that weren't dropped by the retention policy, as told
+///by the zone index (see `ZoneKey`), so that plot names aren't listed
+#[get("/data/strings/zone-names")]
+fn query_zone_names(state: State<Managed>) -> JsonValue {
+    let mut zone_names = FxHashSet::default();
+
+    state.zone_db.for_each_key(|k| if let ZoneKey::Name(name) = k {
+        zone_names.insert(name);
+    });
+
+    list_strings(&state, |k| match k {
+        SCKey::StaticString(key) if zone_names.contains(&key) => Some(key),
+        _ => None
+    })
+}
+
+#[get("/data/strings/threads")]
+fn query_thread_names(state: State<Managed>) -> JsonValue {
+    list_strings(&state, |k| match k {
+        SCKey::ThreadName(key) => Some(key),
+        _ => None
+    })
+}
+
+#[get("/data/strings/plot-names")]
+fn query_plot_names(state: State<Managed>) -> JsonValue {
+    let plot_names: FxHashSet<usize> = state.plot_catalogue.list().into_iter().map(|(key, _)| key).collect();
+
+    list_strings(&state, |k| match k {
+        SCKey::StaticString(key) if plot_names.contains(&key) => Some(key),
+        _ => None
     })
 }
 
 #[get("/data/zones-end")]
 fn query_zones_end(state: State<Managed>) -> JsonValue {
+    let start = state.zone_db.get_min_time();
     let end = state.zone_db.get_max_time();
 
     json!({
         "status": "ok",
+        "start": start,
         "end": end
     })
 }
 
-fn port_validator(s: String) -> Result<(), String> {
-    match s.parse::<u16>() {
-        Ok(_)  => Ok(()),
-        Err(_) => Err("Not a valid port number".to_string())
+///Returns a clap validator for values that must parse as a `T` for which `valid` holds
+fn validator<T: FromStr>(valid: fn(T) -> bool, error: &'static str) -> impl Fn(String) -> Result<(), String> {
+    move |s| match s.parse::<T>() {
+        Ok(x) if valid(x) => Ok(()),
+        _                 => Err(error.to_string())
     }
 }
 
@@ -227,6 +862,56 @@ fn clean_or_create_dir(path: &PathBuf) -> bool {
     true
 }
 
+fn create_dir_if_missing(path: &PathBuf) -> bool {
+    if !path.exists() {
+        if let Err(err) = std::fs::create_dir(path) {
+            error!("Failed to create temporal-lens directory \"{}\": {}", path.to_str().unwrap_or("NON UTF-8 PATH"), err);
+            return false;
+        }
+    }
+
+    true
+}
+
+///Creates a MemDB in `dir`. If `recover` is set, its contents are first
+///recovered from the files a previous instance left in `dir`. If that fails,
+///these files are never deleted: they're moved to `<dir>.broken-<UNIX time>`
+///so that they can be inspected (or recovered by hand), and the MemDB starts
+///from scratch. Returns `None` if they can't be moved.
+fn open_memdb<T>(context: &MemDbContext, name: &str, dir: PathBuf, recover: bool) -> Option<MemDB<T>>
+    where T: FixedLayout + Summarize + Indexed + Columnar
+{
+    if recover {
+        match MemDB::recover(context, name.to_string(), dir.clone()) {
+            Ok(db) => return Some(db),
+            Err(err) => {
+                let empty = std::fs::read_dir(&dir).map(|mut files| files.next().is_none()).unwrap_or(false);
+
+                if empty {
+                    warn!("Could not recover {}: {:?}. Its directory is empty, starting from scratch.", name, err);
+                } else {
+                    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+                    let mut broken_dir = dir.clone().into_os_string();
+                    broken_dir.push(format!(".broken-{}", now));
+
+                    if let Err(rename_err) = std::fs::rename(&dir, &broken_dir) {
+                        error!("Could not recover {}: {:?}, nor move its files aside: {}. Leaving them untouched.", name, err, rename_err);
+                        return None;
+                    }
+
+                    warn!("Could not recover {}: {:?}. Its files were moved to \"{}\", starting from scratch.", name, err, broken_dir.to_str().unwrap_or("NON UTF-8 PATH"));
+
+                    if !create_dir_if_missing(&dir) {
+                        return None;
+                    }
+                }
+            }
+        }
+    }
+
+    Some(MemDB::new(context, name.to_string(), dir))
+}
+
 macro_rules! subdirs {
     ($original:ident, [$($others:literal),+]) => {
         ($({
@@ -238,8 +923,18 @@ macro_rules! subdirs {
     };
 }
 
+///Adds flags that override `$setting` for a single MemDB, e.g. `--zone-seal-span` for `--seal-span`
+macro_rules! per_db_args {
+    ($app:ident, $setting:literal, $long:literal, $validator:expr) => {
+        $app
+        .arg(Arg::with_name(concat!("frame_", $setting)).long(concat!("frame-", $long)).help(concat!("Overrides --", $long, " for frame times")).takes_value(true).validator($validator))
+        .arg(Arg::with_name(concat!("zone_", $setting)).long(concat!("zone-", $long)).help(concat!("Overrides --", $long, " for zones")).takes_value(true).validator($validator))
+        .arg(Arg::with_name(concat!("plot_", $setting)).long(concat!("plot-", $long)).help(concat!("Overrides --", $long, " for plots")).takes_value(true).validator($validator))
+    };
+}
+
 fn main() {
-    let arg_matches = App::new("temporal-lens-server")
+    let app = App::new("temporal-lens-server")
         .version("0.1.0")
         .author("Nicolas Barbotin <nicolas@barbot.in>, Clément Poull")
         .about("Temporal Lens Rust server - Temporal Lens is a telemetry infrastructure for Rust")
@@ -257,7 +952,7 @@ fn main() {
             .short("p")
             .help("Overrides the port used by the server")
             .takes_value(true)
-            .validator(port_validator)
+            .validator(validator::<u16>(|_| true, "Not a valid port number"))
             .default_value("61234")
         )
         .arg(
@@ -266,7 +961,79 @@ fn main() {
             .short("f")
             .help("Disables keep-alive mechanism and never shut the server down automatically")
         )
-        .get_matches();
+        .arg(
+            Arg::with_name("reorder_window")
+            .long("reorder-window")
+            .help("How long, in milliseconds, incoming entries are held back so that out-of-order ones can be sorted")
+            .takes_value(true)
+            .validator(validator::<f64>(|x| x >= 0.0, "Not a valid duration"))
+            .default_value("5")
+        )
+        .arg(
+            Arg::with_name("recover")
+            .long("recover")
+            .short("r")
+            .help("Recovers the capture left in the data directory by a previous instance (e.g. after a crash) instead of wiping it")
+        )
+        .arg(
+            Arg::with_name("retain_seconds")
+            .long("retain-seconds")
+            .help("Drops entries that are older than this many seconds, relative to the latest one. Can be overridden for each database, e.g. with --zone-retain-seconds")
+            .takes_value(true)
+            .validator(validator::<f64>(|x| x > 0.0, "Not a valid duration"))
+        )
+        .arg(
+            Arg::with_name("retain_bytes")
+            .long("retain-bytes")
+            .help("Drops the oldest entries, whichever database they belong to, when their sealed chunks exceed this many bytes together. Chunks that could not be written to disk count as well, while the chunks entries are being pushed into and the string log don't")
+            .takes_value(true)
+            .validator(validator::<u64>(|x| x > 0, "Not a valid size"))
+        )
+        .arg(
+            Arg::with_name("seal_span")
+            .long("seal-span")
+            .help("Seals chunks whose entries span more than this many seconds, even if they aren't full. 0 disables it. Can be overridden for each database, e.g. with --zone-seal-span")
+            .takes_value(true)
+            .validator(validator::<f64>(|x| x >= 0.0, "Not a valid duration"))
+            .default_value("60")
+        )
+        .arg(
+            Arg::with_name("seal_idle")
+            .long("seal-idle")
+            .help("Seals chunks that haven't received any entry for this many seconds, even if they aren't full. 0 disables it. Can be overridden for each database, e.g. with --zone-seal-idle")
+            .takes_value(true)
+            .validator(validator::<f64>(|x| x >= 0.0, "Not a valid duration"))
+            .default_value("10")
+        )
+        .arg(
+            Arg::with_name("max_query_entries")
+            .long("max-query-entries")
+            .help("Maximum number of entries a request may return, all databases together, before being truncated. 0 means no limit")
+            .takes_value(true)
+            .validator(validator::<u64>(|_| true, "Not a valid number"))
+            .default_value("1000000")
+        )
+        .arg(
+            Arg::with_name("max_query_time")
+            .long("max-query-time")
+            .help("Maximum time, in milliseconds, a request may take before being truncated. 0 means no limit")
+            .takes_value(true)
+            .validator(validator::<u64>(|_| true, "Not a valid number"))
+            .default_value("5000")
+        )
+        .arg(
+            Arg::with_name("max_query_chunks")
+            .long("max-query-chunks")
+            .help("Maximum number of chunks a request may read, all databases together, before being truncated. 0 means no limit")
+            .takes_value(true)
+            .validator(validator::<u64>(|_| true, "Not a valid number"))
+            .default_value("1024")
+        );
+
+    let app = per_db_args!(app, "seal_span", "seal-span", validator::<f64>(|x| x >= 0.0, "Not a valid duration"));
+    let app = per_db_args!(app, "seal_idle", "seal-idle", validator::<f64>(|x| x >= 0.0, "Not a valid duration"));
+    let app = per_db_args!(app, "retain_seconds", "retain-seconds", validator::<f64>(|x| x > 0.0, "Not a valid duration"));
+    let arg_matches = app.get_matches();
 
     log4rs::init_file(arg_matches.value_of("logger_config").unwrap(), Default::default()).expect("Failed to load log4rs configuration");
     info!("Starting up...");
@@ -281,7 +1048,10 @@ fn main() {
         }
     }
 
-    if !clean_or_create_dir(&frame_db_dir) || !clean_or_create_dir(&zone_db_dir) || !clean_or_create_dir(&plot_db_dir) {
+    let recover = arg_matches.is_present("recover");
+    let prepare_dir = if recover { create_dir_if_missing } else { clean_or_create_dir };
+
+    if !prepare_dir(&frame_db_dir) || !prepare_dir(&zone_db_dir) || !prepare_dir(&plot_db_dir) {
         return;
     }
 
@@ -293,15 +1063,73 @@ fn main() {
         }
     };
 
-    unsafe {
-        //Safe because we do it before instantiating any MemDB
-        memdb::init();
+    let mut str_log_path = data_dir.clone();
+    str_log_path.push("strings.log");
+
+    let str_collection = if recover {
+        //The string log is left untouched if it can't be recovered
+        StringCollection::recover_log(&str_log_path).unwrap_or_else(|err| {
+            warn!("Failed to recover strings: {:?}. Starting with no strings, which will not be persisted.", err);
+            StringCollection::new()
+        })
+    } else {
+        let mut ret = StringCollection::new();
+
+        if let Err(err) = ret.open_log(&str_log_path) {
+            warn!("Failed to create string log: {:?}. Strings will not be persisted.", err);
+        }
+
+        ret
+    };
+
+    let mut plot_catalogue = Catalogue::new();
+    let mut thread_catalogue = Catalogue::new();
+    let memdb_context = MemDbContext::new();
+    let frame_db: Option<MemDB<LiteFrameData>> = open_memdb(&memdb_context, "frame_db", frame_db_dir, recover);
+    let zone_db: Option<MemDB<LiteZoneData>> = open_memdb(&memdb_context, "zone_db", zone_db_dir, recover);
+    let plot_db: Option<MemDB<LitePlotData>> = open_memdb(&memdb_context, "plot_db", plot_db_dir, recover);
+
+    let (mut frame_db, mut zone_db, mut plot_db) = match (frame_db, zone_db, plot_db) {
+        (Some(frame_db), Some(zone_db), Some(plot_db)) => (frame_db, zone_db, plot_db),
+        _                                              => return
+    };
+
+    if recover {
+        shmem_poller::recover_catalogues(&mut plot_catalogue, &mut thread_catalogue, &zone_db, &plot_db);
     }
 
-    let str_collection = StringCollection::new();
-    let frame_db = unsafe { MemDB::new("frame_db".to_string(), frame_db_dir) };
-    let zone_db = unsafe { MemDB::new("zone_db".to_string(), zone_db_dir) }; //Safe because we called it after `memdb::init()`
-    let plot_db = unsafe { MemDB::new("plot_db".to_string(), plot_db_dir) };
+    let reorder_window = to_nanos(arg_matches.value_of("reorder_window").unwrap().parse::<f64>().unwrap() * 1e-3);
+
+    frame_db.set_reorder_window(reorder_window);
+    zone_db.set_reorder_window(reorder_window);
+    plot_db.set_reorder_window(reorder_window);
+
+    //Value of `setting` for MemDB `db`, e.g. `--zone-seal-span` if specified, `--seal-span` otherwise
+    let db_setting = |db: &str, setting: &str| {
+        arg_matches.value_of(format!("{}_{}", db, setting)).or_else(|| arg_matches.value_of(setting)).map(|s| s.parse::<f64>().unwrap())
+    };
+
+    let seal_span = |db: &str| db_setting(db, "seal_span").filter(|&x| x > 0.0).map(to_nanos);
+    let seal_idle = |db: &str| db_setting(db, "seal_idle").filter(|&x| x > 0.0).map(Duration::from_secs_f64);
+
+    frame_db.set_seal_span(seal_span("frame"));
+    zone_db.set_seal_span(seal_span("zone"));
+    plot_db.set_seal_span(seal_span("plot"));
+    frame_db.set_seal_idle(seal_idle("frame"));
+    zone_db.set_seal_idle(seal_idle("zone"));
+    plot_db.set_seal_idle(seal_idle("plot"));
+
+    let query_limit = |name| match arg_matches.value_of(name).unwrap().parse::<u64>().unwrap() {
+        0 => None,
+        x => Some(x)
+    };
+
+    let limits = BudgetLimits {
+        max_entries: query_limit("max_query_entries").map(|x| x as usize),
+        max_time: query_limit("max_query_time").map(Duration::from_millis),
+        max_chunks: query_limit("max_query_chunks").map(|x| x as usize)
+    };
+
     let start_instant = Instant::now();
 
     let managed = Managed {
@@ -309,11 +1137,21 @@ fn main() {
         zone_db: zone_db.new_accessor(),
         plot_db: plot_db.new_accessor(),
         str_collection: str_collection.new_accessor(),
+        plot_catalogue: plot_catalogue.new_accessor(),
+        thread_catalogue: thread_catalogue.new_accessor(),
+        limits,
         start: start_instant
     };
 
+    let retention = shmem_poller::Retention {
+        frame_seconds: db_setting("frame", "retain_seconds"),
+        zone_seconds: db_setting("zone", "retain_seconds"),
+        plot_seconds: db_setting("plot", "retain_seconds"),
+        bytes: arg_matches.value_of("retain_bytes").map(|s| s.parse().unwrap())
+    };
+
     let opt_start = if arg_matches.is_present("forever") { None } else { Some(start_instant) };
-    shmem_poller::start(shmem, opt_start, str_collection, frame_db, zone_db, plot_db);
+    shmem_poller::start(shmem, opt_start, retention, str_collection, plot_catalogue, thread_catalogue, frame_db, zone_db, plot_db);
     
     if let Err(err) = ctrlc::set_handler(shutdown) {
         warn!("Failed to set Ctrl-C handler: {:?}. Please use the `/shutdown` route to shutdown the server gracefully.", err);
@@ -328,7 +1166,7 @@ fn main() {
 
     debug!("Initialization complete. Igniting rocket...");
     rocket::custom(rocket_cfg)
-        .mount("/", routes![index, info_endpoint, keep_alive_endpoint, shutdown_endpoint, query_frame_times_range, query_frame_times_count, query_plots_endpoint, query_zones_end])
+        .mount("/", routes![index, info_endpoint, health_endpoint, keep_alive_endpoint, shutdown_endpoint, query_frame_times_range, query_frame_times_count, query_plots_endpoint, query_plot_series_list, query_plot_series, query_threads, query_zone_names, query_thread_names, query_plot_names, query_zones_end, query_zone_stats])
         .mount("/public", StaticFiles::from("./public"))
         .manage(managed)
         .attach(AdHoc::on_request("Update keep-alive time", |r, _| {
@@ -340,3 +1178,397 @@ fn main() {
         }))
         .launch();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+
+    const SEEDS: u64 = 8;
+    const QUERIES: usize = 32; //Per seed and per filter
+    const THREADS: usize = 3;
+    const NAMES: usize = 4;
+    const SPAN: u64 = 200_000; //Of the generated zones
+
+    type ZoneRow = (u64, u64, u64, u32, usize, usize); //Entry ID, end, duration, depth, name and thread
+    type SpanRow = (usize, u32, u64, u64, u64, u64); //Thread, depth, start, end, count and busy time
+
+    ///MemDBs, strings and catalogues of a test, filled the way the poller does,
+    ///along with the `Managed` state queries read them through. The directory
+    ///of the MemDBs is removed when the fixture is dropped, even if the test fails.
+    struct Fixture {
+        dir: PathBuf,
+        str_collection: StringCollection,
+        thread_catalogue: Catalogue<ThreadInfo>,
+        plot_catalogue: Catalogue<PlotSeries>,
+        zone_db: MemDB<LiteZoneData>,
+        plot_db: MemDB<LitePlotData>,
+        state: Managed
+    }
+
+    impl Fixture {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("temporal-lens-main-{}-{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            let (frame_db_dir, zone_db_dir, plot_db_dir) = subdirs!(dir, ["frame_db", "zone_db", "plot_db"]);
+
+            for d in &[&frame_db_dir, &zone_db_dir, &plot_db_dir] {
+                std::fs::create_dir_all(d).unwrap();
+            }
+
+            let context = MemDbContext::new();
+            let frame_db: MemDB<LiteFrameData> = MemDB::new(&context, "frame_db".to_string(), frame_db_dir);
+            let zone_db: MemDB<LiteZoneData> = MemDB::new(&context, "zone_db".to_string(), zone_db_dir);
+            let plot_db: MemDB<LitePlotData> = MemDB::new(&context, "plot_db".to_string(), plot_db_dir);
+            let str_collection = StringCollection::new();
+            let plot_catalogue = Catalogue::new();
+            let thread_catalogue = Catalogue::new();
+
+            let state = Managed {
+                frame_db: frame_db.new_accessor(),
+                zone_db: zone_db.new_accessor(),
+                plot_db: plot_db.new_accessor(),
+                str_collection: str_collection.new_accessor(),
+                plot_catalogue: plot_catalogue.new_accessor(),
+                thread_catalogue: thread_catalogue.new_accessor(),
+                limits: BudgetLimits::default(),
+                start: Instant::now()
+            };
+
+            Self { dir, str_collection, thread_catalogue, plot_catalogue, zone_db, plot_db, state }
+        }
+
+        ///Pushes zones, sorted by end time
+        fn push_zones(&mut self, zones: &[TimeData<LiteZoneData>]) {
+            shmem_poller::push_zones(&mut self.thread_catalogue, &mut self.zone_db, zones);
+        }
+
+        ///Pushes (time, name, value) plot samples. Their color is their name.
+        fn push_plots(&mut self, samples: &[(u64, usize, f64)]) {
+            let batch: Vec<_> = samples.iter().map(|&(time, name, value)| TimeData { time, data: LitePlotData::new(name as u32, value, name) }).collect();
+            shmem_poller::push_plots(&mut self.plot_catalogue, &mut self.plot_db, &batch);
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    ///(name, time, value) of the previous sample of each series before `start`
+    fn previous_samples(state: &Managed, start: u64) -> Vec<(usize, u64, f64)> {
+        let mut ret = Vec::new();
+        let cursors = query_previous_samples(state, start, &Budget::new(state.limits), |r| ret.push((r.data.name, r.time, r.data.value)));
+
+        assert!(cursors.is_empty());
+        ret.sort_by_key(|&(name, _, _)| name);
+        ret
+    }
+
+    ///Appends a zone of `thread` that starts at `start`, preceded by its children,
+    ///which are spread over its duration
+    fn nested_zones(rng: &mut StdRng, zones: &mut Vec<TimeData<LiteZoneData>>, thread: usize, start: u64, duration: u64, depth: u32) {
+        let end = start + duration;
+        let mut t = start;
+
+        if depth < 3 {
+            for _ in 0..rng.gen_range(0, 4) {
+                let child = rng.gen_range(0, (end - t) / 2 + 1);
+                nested_zones(rng, zones, thread, t, child, depth + 1);
+                t += child + rng.gen_range(0, (end - t - child) / 2 + 1);
+            }
+        }
+
+        zones.push(TimeData {
+            time: end,
+            data: LiteZoneData {
+                uid: zones.len(),
+                duration,
+                name: rng.gen_range(1, NAMES + 1),
+                thread,
+                color: 0,
+                depth
+            }
+        });
+    }
+
+    ///Fills the zone MemDB of `fixture` with a few threads of nested zones, short
+    ///and long, and names them. Returns the zones along with their entry IDs.
+    fn fill_zones(fixture: &mut Fixture, rng: &mut StdRng) -> Vec<(u64, TimeData<LiteZoneData>)> {
+        let mut zones = Vec::new();
+
+        for thread in 1..=THREADS {
+            let mut t = (thread as u64 - 1) * 100 + rng.gen_range(0, 100); //Threads start in order
+
+            while t < SPAN {
+                let duration = if rng.gen_range(0, 4) == 0 { rng.gen_range(1000, 10000) } else { rng.gen_range(0, 200) };
+                nested_zones(rng, &mut zones, thread, t, duration, 0);
+                t += duration + rng.gen_range(0, 50);
+            }
+
+            fixture.str_collection.insert(SCKey::ThreadName(thread), &format!("thread{}", thread), 0);
+        }
+
+        for name in 1..=NAMES {
+            fixture.str_collection.insert(SCKey::StaticString(name), &format!("zone{}", name), 0);
+        }
+
+        //Children come first when they end with their parent
+        zones.sort_by_key(|z| z.time);
+
+        //A few sealed chunks of several summary blocks, and a current one
+        fixture.zone_db.set_seal_span(Some(SPAN / 4));
+        fixture.push_zones(&zones);
+        fixture.zone_db.flush();
+
+        let mut ret = Vec::new();
+        fixture.state.zone_db.query(0, None, |k, r| ret.push((k, *r)));
+        assert_eq!(ret.len(), zones.len());
+        ret
+    }
+
+    fn lod_rows(zones: &[ReconstructedZoneData], busy: &[(usize, u32, BusySpan)]) -> (Vec<ZoneRow>, Vec<SpanRow>) {
+        let zones = zones.iter().map(|z| (z.entry_id, z.end, z.duration, z.depth, z.name, z.thread)).collect();
+        let mut busy: Vec<_> = busy.iter().map(|&(thread, depth, s)| (thread, depth, s.start, s.end, s.count, s.busy)).collect();
+
+        busy.sort_unstable();
+        (zones, busy)
+    }
+
+    ///Zones from `start` to `end`, plus those after `end` that queries go through
+    ///before stopping (see `ShouldStopQuery`)
+    fn naive_range(all: &[(u64, TimeData<LiteZoneData>)], start: u64, end: u64) -> impl Iterator<Item = &(u64, TimeData<LiteZoneData>)> {
+        let in_range = all.iter().filter(move |(_, r)| r.time >= start && r.time <= end);
+        let tail = all.iter().skip_while(move |(_, r)| r.time <= end).take_while(move |(_, r)| !(r.data.depth == 0 && r.time - r.data.duration > end));
+
+        in_range.chain(tail)
+    }
+
+    ///What `query_zones()` should return when it isn't truncated
+    fn naive_zones(all: &[(u64, TimeData<LiteZoneData>)], start: u64, end: u64, filter: &ZoneFilter) -> Vec<ZoneRow> {
+        let zones: Vec<_> = naive_range(all, start, end).filter(|(_, r)| filter.matches(&r.data)).map(|(k, r)| r.data.reconstruct(r.time, *k)).collect();
+        lod_rows(&zones, &[]).0
+    }
+
+    ///What `query_zones_lod()` should return: each zone of `naive_range()` that matches
+    ///`filter` is either returned as-is if it lasts at least `resolution`, or merged into
+    ///the span of its thread and depth in its bucket. Those after `end` go in the last one.
+    fn naive_lod(all: &[(u64, TimeData<LiteZoneData>)], start: u64, end: u64, resolution: u64, filter: &ZoneFilter) -> (Vec<ZoneRow>, Vec<SpanRow>) {
+        let last_bucket = (end - start) / resolution;
+        let mut zones = Vec::new();
+        let mut busy: FxHashMap<(usize, u32, u64), BusySpan> = Default::default();
+
+        for (k, r) in naive_range(all, start, end) {
+            let (k, bucket) = (*k, u64::min((r.time - start) / resolution, last_bucket));
+
+            if !filter.matches(&r.data) {
+                continue;
+            }
+
+            if r.data.duration >= resolution {
+                zones.push(r.data.reconstruct(r.time, k));
+            } else {
+                let span = BusySpan::new(r.time, r.data.duration);
+                busy.entry((r.data.thread, r.data.depth, bucket)).and_modify(|dst| dst.merge(&span)).or_insert(span);
+            }
+        }
+
+        let busy: Vec<_> = busy.into_iter().map(|((thread, depth, _), span)| (thread, depth, span)).collect();
+        lod_rows(&zones, &busy)
+    }
+
+    ///Compares `query_zones_lod()` with a naive per-zone merge, with and without
+    ///filters, over ranges and resolutions that make it use the summaries of whole
+    ///chunks and blocks as well as single zones. Filtering by name goes through
+    ///the index instead, and is resumed from its cursor when truncated.
+    #[test]
+    fn lod_matches_naive_merge() {
+        for seed in 0..SEEDS {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mut fixture = Fixture::new(&format!("lod-{}", seed));
+            let all = fill_zones(&mut fixture, &mut rng);
+            let state = &fixture.state;
+
+            let mut summaries = 0;
+            state.zone_db.query_buckets(0, SPAN, SPAN + 1, None, |_, item| if let BucketItem::Summary(..) = item { summaries += 1; });
+            assert!(summaries > 0, "seed {}: no summary to use", seed);
+
+            for &query in &["", "max_depth=1", "thread=thread1&thread=thread3", "thread_id=2&max_depth=0", "name=zone1&name_id=3"] {
+                let filter = ZoneFilterParams::parse(query).unwrap().resolve(&state.str_collection, &state.thread_catalogue);
+
+                for _ in 0..QUERIES {
+                    let (a, b) = (rng.gen_range(0, SPAN + 20000), rng.gen_range(0, SPAN + 20000));
+                    let start = u64::min(a, b);
+
+                    //The index doesn't look at every zone after `end`, so they are left out
+                    let end = if filter.has_names() { SPAN + 20000 } else { u64::max(a, b) };
+
+                    let resolution = match rng.gen_range(0, 3) {
+                        0 => rng.gen_range(1, 100),
+                        1 => rng.gen_range(100, 5000),
+                        _ => (end - start) / rng.gen_range(1, 20) + 1
+                    };
+
+                    let expected = naive_lod(&all, start, end, resolution, &filter);
+                    let (zones, busy, next_start, next_cursor) = query_zones_lod(state, start, end, resolution, None, &filter, &Budget::new(state.limits));
+
+                    assert_eq!((next_start, next_cursor), (None, None), "seed {}: query_zones_lod({}, {}, {}, \"{}\") is truncated", seed, start, end, resolution, query);
+                    assert_eq!(lod_rows(&zones, &busy), expected, "seed {}: query_zones_lod({}, {}, {}, \"{}\")", seed, start, end, resolution, query);
+
+                    if !filter.has_names() {
+                        continue;
+                    }
+
+                    //Resumed from its cursor until it's over, pages may split spans but not zones
+                    let limits = BudgetLimits { max_entries: Some(rng.gen_range(1, 200)), ..Default::default() };
+                    let mut cursor = None;
+                    let mut pages = (Vec::new(), Vec::new());
+
+                    loop {
+                        let (zones, busy, next_start, next_cursor) = query_zones_lod(state, start, end, resolution, cursor, &filter, &Budget::new(limits));
+
+                        assert_eq!(next_start, None, "seed {}: query_zones_lod({}, {}, {}, \"{}\") returned a time to resume from", seed, start, end, resolution, query);
+                        assert!(next_cursor.map(|next| cursor.map(|c| next > c).unwrap_or(true)).unwrap_or(true), "seed {}: query_zones_lod({}, {}, {}, \"{}\") makes no progress", seed, start, end, resolution, query);
+
+                        pages.0.extend(zones);
+                        pages.1.extend(busy);
+
+                        match next_cursor {
+                            Some(next) => cursor = Some(next),
+                            None       => break
+                        }
+                    }
+
+                    let mut totals: FxHashMap<(usize, u32), (u64, u64)> = Default::default();
+                    let mut expected_totals = totals.clone();
+
+                    for &(thread, depth, span) in &pages.1 {
+                        let total = totals.entry((thread, depth)).or_default();
+                        *total = (total.0 + span.count, total.1 + span.busy);
+                    }
+
+                    for &(thread, depth, _, _, count, busy) in &expected.1 {
+                        let total = expected_totals.entry((thread, depth)).or_default();
+                        *total = (total.0 + count, total.1 + busy);
+                    }
+
+                    assert_eq!(lod_rows(&pages.0, &[]).0, expected.0, "seed {}: zones of resumed query_zones_lod({}, {}, {}, \"{}\")", seed, start, end, resolution, query);
+                    assert_eq!(totals, expected_totals, "seed {}: spans of resumed query_zones_lod({}, {}, {}, \"{}\")", seed, start, end, resolution, query);
+                }
+            }
+        }
+    }
+
+    ///Lists zones with filters that go through the `zone_db` index (names or threads)
+    ///or not, and checks that names resolve to the right keys, that repeated threads
+    ///add up, and that `max_depth` applies either way.
+    #[test]
+    fn zone_filters_match_naive_filter() {
+        let filters: &[(&str, Option<&[usize]>, Option<&[usize]>, Option<u32>)] = &[
+            ("max_depth=1",                                   None,              None,              Some(1)),
+            ("thread=thread1&thread=thread3&max_depth=2",     None,              Some(&[1, 3][..]), Some(2)),
+            ("name=zone2&max_depth=0",                        Some(&[2][..]),    None,              Some(0)),
+            ("name=zone1&name=zone4&thread_id=2&max_depth=1", Some(&[1, 4][..]), Some(&[2][..]),    Some(1)),
+            ("name=zone1&name_id=3&name=unknown",             Some(&[1, 3][..]), None,              None),
+            ("name=unknown",                                  Some(&[][..]),     None,              None)
+        ];
+
+        for seed in 0..SEEDS {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mut fixture = Fixture::new(&format!("filters-{}", seed));
+            let all = fill_zones(&mut fixture, &mut rng);
+            let state = &fixture.state;
+
+            for &(query, names, threads, max_depth) in filters {
+                let filter = ZoneFilterParams::parse(query).unwrap().resolve(&state.str_collection, &state.thread_catalogue);
+                let indexed = names.is_some() || threads.is_some();
+                assert_eq!(filter.index_keys().is_some(), indexed, "seed {}: \"{}\" doesn't use the index as expected", seed, query);
+
+                //Zones the filter should match, given the keys of the names
+                let all_keys: Vec<_> = all.iter().filter(|(_, r)| {
+                    names.map(|names| names.contains(&r.data.name)).unwrap_or(true)
+                        && threads.map(|threads| threads.contains(&r.data.thread)).unwrap_or(true)
+                        && max_depth.map(|d| r.data.depth <= d).unwrap_or(true)
+                }).map(|(k, _)| *k).collect();
+
+                for _ in 0..QUERIES {
+                    let (a, b) = (rng.gen_range(0, SPAN + 20000), rng.gen_range(0, SPAN + 20000));
+                    let start = u64::min(a, b);
+
+                    //The index doesn't look at every zone after `end`, so they are left out
+                    let end = if indexed { SPAN + 20000 } else { u64::max(a, b) };
+
+                    let expected = naive_zones(&all, start, end, &filter);
+                    let (zones, next_cursor) = query_zones(state, start, end, None, None, &filter, &Budget::new(state.limits));
+
+                    assert_eq!(next_cursor, None, "seed {}: query_zones({}, {}, \"{}\") is truncated", seed, start, end, query);
+                    assert_eq!(lod_rows(&zones, &[]).0, expected, "seed {}: query_zones({}, {}, \"{}\")", seed, start, end, query);
+                    assert!(expected.iter().all(|z| all_keys.binary_search(&z.0).is_ok()), "seed {}: \"{}\" matches unexpected zones", seed, query);
+                }
+
+                let (zones, _) = query_zones(state, 0, SPAN + 20000, None, None, &filter, &Budget::new(state.limits));
+                assert_eq!(lod_rows(&zones, &[]).0.into_iter().map(|z| z.0).collect::<Vec<_>>(), all_keys, "seed {}: \"{}\" doesn't match every zone expected", seed, query);
+            }
+        }
+    }
+
+    ///Lists threads sorted by their first zone, with their name if it's known
+    #[test]
+    fn threads_are_listed_by_first_zone() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut fixture = Fixture::new("threads");
+        let mut all = fill_zones(&mut fixture, &mut rng);
+
+        //Unnamed, and last
+        let mut unnamed = Vec::new();
+        nested_zones(&mut rng, &mut unnamed, THREADS + 1, all.last().unwrap().1.time + 1, 10, 3);
+        fixture.push_zones(&unnamed);
+        fixture.zone_db.flush();
+        all.extend(unnamed.into_iter().map(|r| (0, r)));
+
+        let expected: Vec<_> = (1..=THREADS + 1).map(|thread| {
+            let zones: Vec<_> = all.iter().map(|(_, r)| r).filter(|r| r.data.thread == thread).collect();
+            let name = if thread <= THREADS { format!("thread{}", thread) } else { "????".to_string() };
+
+            json!({
+                "key": thread,
+                "name": name,
+                "first": zones.iter().map(|r| r.time - r.data.duration).min().unwrap(),
+                "last": zones.iter().map(|r| r.time).max().unwrap(),
+                "zones": zones.len()
+            })
+        }).collect();
+
+        assert_eq!(list_threads(&fixture.state).0, json!({ "status": "ok", "threads": expected }).0);
+    }
+
+    ///Interleaves two plot series, some samples of which arrive out of order or
+    ///too late, and checks that each series gets its own previous sample.
+    #[test]
+    fn previous_sample_per_series() {
+        let mut fixture = Fixture::new("previous");
+        fixture.plot_db.set_reorder_window(100);
+
+        fixture.push_plots(&[(10, 1, 1.0), (20, 2, 2.0), (30, 1, 3.0), (40, 2, 4.0)]);
+        fixture.push_plots(&[(60, 2, 6.0), (50, 1, 5.0)]);
+        fixture.push_plots(&[(45, 1, 7.0)]); //Older than the last sample of its series, but within the reorder window
+        fixture.plot_db.flush();
+        fixture.push_plots(&[(5, 1, 8.0)]); //Late, dropped
+        fixture.plot_db.flush();
+
+        let mut series = fixture.state.plot_catalogue.list();
+        series.sort_by_key(|&(name, _)| name);
+        let series: Vec<_> = series.into_iter().map(|(name, s)| (name, s.count, s.first, s.last)).collect();
+        assert_eq!(series, vec![(1, 4, 10, 50), (2, 3, 20, 60)]);
+
+        assert_eq!(previous_samples(&fixture.state, 5), vec![]);
+        assert_eq!(previous_samples(&fixture.state, 15), vec![(1, 10, 1.0)]);
+        assert_eq!(previous_samples(&fixture.state, 35), vec![(1, 30, 3.0), (2, 20, 2.0)]);
+        assert_eq!(previous_samples(&fixture.state, 48), vec![(1, 45, 7.0), (2, 40, 4.0)]);
+        assert_eq!(previous_samples(&fixture.state, 55), vec![(1, 50, 5.0), (2, 40, 4.0)]);
+        assert_eq!(previous_samples(&fixture.state, 1000), vec![(1, 50, 5.0), (2, 60, 6.0)]);
+    }
+}
@@ -1,609 +1,3392 @@
-use temporal_lens::shmem::ShouldStopQuery;
+use std::time::{Instant, Duration};
+use std::sync::{RwLock, Arc, Mutex};
+use std::sync::mpsc::{self, SyncSender};
+use std::sync::atomic::{AtomicU8, AtomicU64, AtomicUsize, Ordering};
+use std::collections::VecDeque;
+use std::cell::{UnsafeCell, Cell};
+use std::mem::MaybeUninit;
+use std::borrow::Cow;
+use std::hash::Hash;
+use std::fmt::Debug;
+use std::path::{Path, PathBuf};
+use std::io::{Write, Error as IOResult};
+use std::fs;
+
+use bincode::Error as BincodeError;
+use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
+use memmap::Mmap;
+use fxhash::FxHashMap;
+use log::{error, warn, info, debug};
+
+#[derive(Serialize, Deserialize, Copy, Clone)]
+#[repr(C)]
+pub struct TimeData<T, K = u64> {
+    pub time: K,
+    pub data: T
+}
+
+///Type of the `time` field entries are sorted by. Spans of time (reorder
+///windows, bucket widths...) are expressed with the same type. The server
+///uses `u64` nanoseconds, which keep their precision whatever the length of
+///the capture.
+pub trait TimeKey: FixedLayout + Ord + Default + Debug + Send + Sync + Serialize + DeserializeOwned {
+    ///So that it can be stored in an `AtomicU64`
+    fn to_bits(self) -> u64;
+    fn from_bits(bits: u64) -> Self;
+
+    fn saturating_add(self, span: Self) -> Self;
+    fn saturating_sub(self, span: Self) -> Self;
+
+    ///Only used to split time ranges into buckets
+    fn as_f64(self) -> f64;
+}
+
+///Entries such as zones may end after the max of a query and yet be part of
+///it. Queries scanning past their max keep going until this returns true.
+pub trait ShouldStopQuery<K = u64> {
+    fn should_stop_query(&self, t: K, query_max: K) -> bool;
+}
+
+///Types stored in a MemDB must be able to summarize a block of
+///consecutive entries. These summaries are computed when a chunk
+///gets sealed (for the whole chunk, and for each block of
+///`SUMMARY_BLOCK_SIZE` entries) and are kept in RAM, even when the
+///chunk itself isn't, so that aggregation queries can use them
+///instead of visiting every single entry.
+///
+///Use `()` as `Summary` if you don't need it.
+pub trait Summarize<K = u64>: Sized {
+    type Summary;
+
+    fn summarize(data: &[TimeData<Self, K>]) -> Self::Summary;
+}
+
+///Types stored in a MemDB may be looked up by keys (e.g. a name or a
+///thread) that are indexed when a chunk gets sealed. Each sealed chunk
+///remembers which of its blocks of `SUMMARY_BLOCK_SIZE` entries contain
+///each key, so that queries looking for a few keys can skip the chunks
+///and blocks that don't contain them without reading them from disk.
+///See `QueryIter::with_keys()`.
+///
+///Use `()` as `Key` and do nothing in `index_keys()` if you don't need it.
+pub trait Indexed {
+    type Key: Copy + Eq + Hash;
+
+    ///Calls `f` with every key of this entry
+    fn index_keys<F: FnMut(Self::Key)>(&self, f: F);
+
+    fn has_any_key(&self, keys: &[Self::Key]) -> bool {
+        let mut ret = false;
+        self.index_keys(|k| ret |= keys.contains(&k));
+
+        ret
+    }
+}
+
+///Types stored in a MemDB are written to disk as raw bytes when their
+///chunk gets sealed, and memory-mapped back so that queries only touch
+///the pages they need.
+///
+///Unsafe because implementors must be plain data: no pointers, no
+///references, no heap allocations, and any bit pattern written by the
+///same build of the server must be a valid value. They must not have
+///any padding bytes either, since those would be uninitialized when
+///written to disk and checksummed: reorder the fields or add explicit
+///padding fields. `#[repr(C)]` is strongly advised so that the layout
+///doesn't depend on the compiler.
+pub unsafe trait FixedLayout: Copy + 'static {
+    ///Identifies the type in chunk headers, so that chunk files of another
+    ///type aren't loaded. Must be unique, and never change once released.
+    const TYPE_ID: u32;
+}
+
+macro_rules! fixed_layout_primitives {
+    ($($t:ty = $id:expr),+) => {
+        $(unsafe impl FixedLayout for $t { const TYPE_ID: u32 = $id; })+
+    };
+}
+
+//So that they can be used as columns, see `ColumnView::column()`
+fixed_layout_primitives!(u8 = 1, u16 = 2, u32 = 3, u64 = 4, usize = 5, i8 = 6, i16 = 7, i32 = 8, i64 = 9, isize = 10, f32 = 11, f64 = 12);
+
+impl TimeKey for u64 {
+    #[inline]
+    fn to_bits(self) -> u64 {
+        self
+    }
+
+    #[inline]
+    fn from_bits(bits: u64) -> Self {
+        bits
+    }
+
+    #[inline]
+    fn saturating_add(self, span: u64) -> u64 {
+        u64::saturating_add(self, span)
+    }
+
+    #[inline]
+    fn saturating_sub(self, span: u64) -> u64 {
+        u64::saturating_sub(self, span)
+    }
+
+    #[inline]
+    fn as_f64(self) -> f64 {
+        self as f64
+    }
+}
+
+///Types stored in a MemDB may be stored column by column (i.e. as a struct
+///of arrays) in chunk files, instead of entry by entry. Aggregations that only
+///need a few fields can then scan contiguous columns (see `Accessor::scan_columns()`)
+///instead of dragging every field of every entry through the cache. Other queries
+///rebuild the entries they read from the columns.
+///
+///`COLUMNS` lists the size of each column (i.e. of the field it stores). Since
+///entries are rebuilt from them, columns must cover every field. `write_column()`
+///appends the raw bytes of a column for the given entries, and `read_rows()` does
+///the opposite for all the columns. Leave `COLUMNS` empty to store entries as-is.
+pub trait Columnar: Sized {
+    const COLUMNS: &'static [usize] = &[];
+
+    fn write_column<K>(_data: &[TimeData<Self, K>], _column: usize, _out: &mut Vec<u8>) {}
+
+    ///Appends the entries of `view` to `out`
+    fn read_rows<K: TimeKey>(_view: &ColumnView<K>, _out: &mut Vec<TimeData<Self, K>>) {}
+}
+
+///Consecutive entries of a chunk seen column by column, as passed to the
+///`Accessor::scan_columns()` callback
+pub struct ColumnView<'a, K = u64>
+{
+    times: &'a [K],
+    columns: Vec<&'a [u8]> //Same order as `Columnar::COLUMNS`
+}
+
+///Entries of a chunk, as seen by queries. Those of chunk files that store
+///columns (see `Columnar`) are only rebuilt when asked for, and their times
+///are read in place, so that finding a few entries doesn't rebuild the whole chunk.
+enum Rows<'a, T, K>
+{
+    Slice(&'a [TimeData<T, K>]),
+    Columns(ColumnView<'a, K>)
+}
+
+///Item passed to the `query_buckets()` callback
+///Along with the entry ID of the (first) entry it refers to
+pub enum BucketItem<'a, T: Summarize<K>, K = u64> {
+    Summary(u64, &'a T::Summary),
+    Entry(u64, &'a TimeData<T, K>)
+}
+
+struct Block<S, K> {
+    min: K,
+    max: K,
+    summary: S
+}
+
+///Entries of a sealed chunk
+enum ChunkData<T, K> {
+    Mapped(Arc<MappedFile>, usize), //Chunk file and entry count. Shared with the prefetcher
+    Heap(Vec<TimeData<T, K>>), //Only if the chunk could not be written to disk
+    Dropped //By the retention policy, see `MemDB::drop_chunks_before()`
+}
+
+///Chunk file mapped in memory, header included. Nothing prevents other processes
+///from changing it under our feet, and reading pages of a mapping that are past
+///the end of its file kills the process (SIGBUS). So its size is checked before
+///every access, and its checksum on first access, as it gets paged in anyway.
+///Once a check fails, the file is considered broken for good: its entries are
+///missing from query results, and `Accessor::get_health()` reports it.
+struct MappedFile
+{
+    mapping: Mmap,
+    file: fs::File, //Kept open to check its size
+    path: PathBuf, //For error messages
+    checksum: u32, //See `ChunkHeader::checksum`
+    state: AtomicU8, //FILE_UNCHECKED, FILE_CHECKED or FILE_BROKEN
+    error: Mutex<Option<String>> //Why the file is broken
+}
+
+///A sealed chunk. It is immutable: its entries are memory-mapped,
+///and the OS decides which of them actually stay in RAM.
+struct Chunk<T: Summarize<K> + Indexed, K> {
+    data: ChunkData<T, K>,
+    summary: T::Summary,
+    blocks: Vec<Block<T::Summary, K>>, //One for each SUMMARY_BLOCK_SIZE entries
+    index: FxHashMap<T::Key, u64>, //Bit i is set if block i contains the key
+    count: usize,
+    min: K,
+    max: K
+}
+
+///The chunk new entries are pushed into. Its storage never grows
+///in place, so that entries never move: readers can access the
+///published ones (i.e. the first `len`) while new ones are being
+///written. Once full, it is copied into a bigger one, see
+///`MemDB::grow_current_chunk()`.
+struct CurrentChunk<T, K>
+{
+    entries: Box<[UnsafeCell<MaybeUninit<TimeData<T, K>>>]>,
+    len: AtomicUsize
+}
+
+///Chunk list as seen by a query. Cloning it is cheap, and holding
+///it doesn't prevent the MemDB from sealing chunks.
+struct Snapshot<T: Summarize<K> + Indexed, K>
+{
+    first_live: usize, //Chunks before this one were dropped
+    old_chunks: Arc<Vec<Arc<Chunk<T, K>>>>,
+    current_chunk: Arc<CurrentChunk<T, K>>
+}
+
+struct Contents<T: Summarize<K> + Indexed, K>
+{
+    snapshot: RwLock<Snapshot<T, K>>, //Only write-locked when a chunk gets sealed
+    max: AtomicU64, //Bits of the time of the last entry, see `TimeKey::to_bits()`
+    late: AtomicU64, //Number of entries that arrived after the reorder window
+    failed_chunks: Mutex<Vec<ChunkFailure<K>>>, //Chunks that could not be persisted or recovered
+    prefetcher: Prefetcher,
+    save_path: PathBuf,
+    name: String
+}
+
+///Direction the client is about to scroll towards, see `Accessor::prefetch()`
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum PrefetchHint
+{
+    Left,
+    Right
+}
+
+///Pages memory-mapped chunks in from a background thread, so that queries
+///don't have to wait for the disk. The thread stops once the MemDB and all
+///of its accessors are dropped.
+struct Prefetcher
+{
+    sender: Mutex<SyncSender<Arc<MappedFile>>> //Bounded, requests are dropped when the thread lags behind
+}
+
+pub struct Accessor<T: Summarize<K> + Indexed, K = u64>
+{
+    contents: Arc<Contents<T, K>>,
+    last_range: Mutex<Option<(K, K)>> //Of the last prefetch, to guess where the next query will be
+}
+
+#[derive(Copy, Clone)]
+enum IterStart<K>
+{
+    Time(K),
+    Index(usize)
+}
+
+///Iterator over the entries of a MemDB, see `Accessor::iter()`
+pub struct QueryIter<'a, T: Summarize<K> + Indexed, K = u64>
+{
+    snapshot: Snapshot<T, K>, //Taken once for the whole iteration
+    cid: usize,
+    start: IterStart<K>,
+    max: K,
+    buffer: VecDeque<(u64, TimeData<T, K>)>,
+    keys: Option<Vec<T::Key>>,
+    budget: Option<&'a Budget>,
+    truncated: bool, //Stopped because `budget` was exceeded
+    done: bool
+}
+
+///Limits on the work a query may do, see `Budget`
+#[derive(Debug, Copy, Clone, Default)]
+pub struct BudgetLimits
+{
+    pub max_entries: Option<usize>,
+    pub max_time: Option<Duration>,
+    pub max_chunks: Option<usize> //Sealed chunks read, i.e. not skipped
+}
+
+///Which limit of a `Budget` was exceeded
+#[derive(Debug, Copy, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BudgetExceeded
+{
+    Entries,
+    WallTime,
+    Chunks
+}
+
+///Keeps track of the work done by one or more queries (typically those of
+///a single REST request) and stops them once `BudgetLimits` are exceeded.
+///See `QueryIter::with_budget()`, as well as the `budget` arguments of
+///`Accessor::query_buckets()`, `scan_columns()`, `query_count()` and `query_previous_matching()`.
+///
+///Iterators always stop on an entry or chunk boundary, so that they can
+///be resumed from `QueryIter::next_cursor()`. To guarantee progress, the
+///first chunk is always read, whatever the limits.
+pub struct Budget
+{
+    limits: BudgetLimits,
+    start: Instant,
+    entries: Cell<usize>,
+    chunks: Cell<usize>,
+    exceeded: Cell<Option<BudgetExceeded>>
+}
+
+///Source of real time for MemDBs, which only use it to decide when the
+///current chunk has been idle for long enough (see `MemDB::seal_if_idle()`).
+///Times are relative to an arbitrary origin, e.g. the creation of the clock.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Duration;
+}
+
+///`Clock` backed by `Instant`, the one the server uses
+pub struct SystemClock(Instant);
+
+///`Clock` that only moves when told to, so that idle sealing can be
+///tested without waiting
+#[derive(Default)]
+pub struct ManualClock(AtomicU64); //In nanoseconds
+
+///State shared by MemDBs of the same session, passed to `MemDB::new()` and
+///`MemDB::recover()`. Cheap to clone.
+#[derive(Clone)]
+pub struct MemDbContext
+{
+    clock: Arc<dyn Clock>
+}
+
+///Describes a sealed chunk, as stored in the manifest
+#[derive(Serialize, Deserialize)]
+struct ManifestEntry<K>
+{
+    id: usize,
+    min: K,
+    max: K,
+    count: usize,
+    checksum: u32, //CRC32 of the entries
+    dropped: bool
+}
+
+pub struct MemDB<T: Summarize<K> + Indexed, K = u64> {
+    context: MemDbContext,
+    contents: Arc<Contents<T, K>>,
+    manifest: Vec<ManifestEntry<K>>,
+    current_chunk: Arc<CurrentChunk<T, K>>, //Same as the snapshot's, saves us a lock
+    max: K,
+    pending: Vec<TimeData<T, K>>, //Sorted entries waiting for the reorder window to pass
+    reorder_window: K,
+    stored_bytes: u64, //Size of the chunks that weren't dropped
+    current_min: K, //Time of the first entry of the current chunk
+    last_push: Duration, //See `Clock::now()`
+    seal_span: Option<K>,
+    seal_idle: Option<Duration>,
+    late_warning: Option<Duration>, //When late entries were last logged, see `Clock::now()`
+    unreported_late: u64 //Late entries that weren't logged yet
+}
+
+const SWAP_THRESHOLD: usize = 32768;
+const SUMMARY_BLOCK_SIZE: usize = 512; //SWAP_THRESHOLD / SUMMARY_BLOCK_SIZE must not exceed 64, see `Chunk::index`
+const _: [(); 0] = [(); (SWAP_THRESHOLD / SUMMARY_BLOCK_SIZE > 64) as usize];
+const MIN_CURRENT_CAPACITY: usize = SUMMARY_BLOCK_SIZE; //Entries allocated for a new current chunk, at least
+const CHUNK_MAGIC: u32 = 0x4B43_4C54; //"TLCK"
+const CHUNK_FORMAT_VERSION: u32 = 7;
+const CHUNK_HEADER_SIZE: usize = 32; //Keeps the entries aligned in the mapping
+const PREFETCH_MAX_CHUNKS: usize = 4; //Per query
+const PREFETCH_QUEUE_SIZE: usize = 16;
+const BUDGET_CLOCK_INTERVAL: usize = 1024; //Entries charged between two looks at the clock, see `Budget::charge_entries()`
+const PAGE_SIZE: usize = 4096;
+const LATE_WARNING_INTERVAL: Duration = Duration::from_secs(10); //Late entries are logged at most this often
+const FILE_UNCHECKED: u8 = 0; //Checksum not checked yet, see `MappedFile`
+const FILE_CHECKED: u8 = 1;
+const FILE_BROKEN: u8 = 2;
+const MANIFEST_FILE: &str = "manifest";
+const MANIFEST_TMP_FILE: &str = "manifest.tmp";
+
+#[derive(Debug)]
+enum ChunkSaveError
+{
+    FileCreateError(IOResult),
+    SerializeError(BincodeError),
+    FileWriteError(IOResult),
+    FileSyncError(IOResult),
+    MapError(ChunkLoadError),
+    ManifestRenameError(IOResult)
+}
+
+#[derive(Debug)]
+enum ChunkLoadError
+{
+    FileOpenError(IOResult),
+    MapError(IOResult),
+    DeserializeError(BincodeError),
+    BadMagic,
+    UnsupportedVersion(u32),
+    TypeMismatch,
+    ChecksumMismatch,
+    CountMismatch,
+    SizeChanged(u64), //Since the file was mapped, see `MappedFile`
+    FileCheckError(IOResult)
+}
+
+///Written at the beginning of each chunk file, followed by either the
+///raw entries or their columns (see `Columnar`). Serializes to exactly
+///`CHUNK_HEADER_SIZE` bytes.
+#[derive(Serialize, Deserialize)]
+struct ChunkHeader
+{
+    magic: u32,
+    version: u32,
+    type_id: u32, //See `FixedLayout::TYPE_ID`
+    entry_size: u32,
+    count: u64,
+    checksum: u32, //CRC32 of everything after the header, checked on first access, see `MappedFile`
+    columns: u32 //Number of column sections instead of the entries, see `column_layout()`
+}
+
+///A chunk that could not be persisted (and stays in RAM), or that could not
+///be recovered or whose file broke since (and is missing), as listed by
+///`Accessor::get_health()`
+#[derive(Serialize, Clone)]
+pub struct ChunkFailure<K = u64>
+{
+    pub chunk: usize,
+    pub min: K,
+    pub max: K,
+    pub count: usize,
+    pub error: String
+}
+
+#[derive(Debug)]
+pub enum RecoveryError
+{
+    ManifestOpenError(IOResult),
+    ManifestDeserializeError(BincodeError)
+}
+
+///Writes `bytes` to a new file at `path` and makes sure they reached the disk
+fn write_synced(path: &Path, bytes: &[u8]) -> Result<(), ChunkSaveError> {
+    let mut file = fs::File::create(path).map_err(ChunkSaveError::FileCreateError)?;
+    let result = file.write_all(bytes).map_err(ChunkSaveError::FileWriteError).and_then(|_| file.sync_all().map_err(ChunkSaveError::FileSyncError));
+
+    if result.is_err() {
+        drop(file); //Make sure its closed otherwise we won't be able to delete it
+
+        if let Err(remove_err) = fs::remove_file(path) {
+            warn!("Failed to write file to disk, and then failed to remove it!! Things will go wrong... error: {}", remove_err);
+        }
+    }
+
+    result
+}
+
+impl<T: FixedLayout + Summarize<K> + Indexed + Columnar, K: TimeKey> Chunk<T, K> {
+    ///Placeholder for a chunk dropped by the retention policy, or that couldn't
+    ///be recovered. It keeps its time range, so that chunk indices (and thus
+    ///entry IDs) don't change.
+    fn dropped(min: K, max: K) -> Self {
+        Self {
+            data: ChunkData::Dropped,
+            summary: T::summarize(&[]),
+            blocks: Vec::new(),
+            index: Default::default(),
+            count: 0,
+            min,
+            max
+        }
+    }
+
+    fn size(&self) -> u64 {
+        match self.data {
+            ChunkData::Dropped => 0,
+            _ => column_layout::<T, K>(self.count).1 as u64
+        }
+    }
+
+    ///Summarizes and indexes `data`, whose entries are read (or rebuilt from their columns) once
+    fn new(data: ChunkData<T, K>) -> Self {
+        let mut ret = Self::with_entries(&data.entries());
+        ret.data = data;
+
+        ret
+    }
+
+    ///Summarizes and indexes `entries`. The data of the returned chunk must then be set.
+    fn with_entries(entries: &[TimeData<T, K>]) -> Self {
+        let blocks = entries.chunks(SUMMARY_BLOCK_SIZE).map(|block| Block {
+            min: block[0].time,
+            max: block[block.len() - 1].time,
+            summary: T::summarize(block)
+        }).collect();
+
+        let mut index = FxHashMap::default();
+
+        for (i, entry) in entries.iter().enumerate() {
+            let bit = 1u64 << (i / SUMMARY_BLOCK_SIZE);
+            entry.data.index_keys(|k| *index.entry(k).or_insert(0) |= bit);
+        }
+
+        Self {
+            data: ChunkData::Dropped,
+            summary: T::summarize(entries),
+            blocks,
+            index,
+            count: entries.len(),
+            min: entries.first().unwrap().time,
+            max: entries.last().unwrap().time
+        }
+    }
+
+    ///Only meant for entries and times
+    fn as_bytes<U: Copy>(data: &[U]) -> &[u8] {
+        //Safe because T and K are plain data (see `FixedLayout`)
+        unsafe { std::slice::from_raw_parts(data.as_ptr() as *const u8, data.len() * std::mem::size_of::<U>()) }
+    }
+
+    ///Turns entries into the contents of a chunk file
+    fn encode(data: &[TimeData<T, K>]) -> Result<Vec<u8>, BincodeError> {
+        debug_assert_eq!(std::mem::size_of::<TimeData<T, K>>(), std::mem::size_of::<T>() + std::mem::size_of::<K>(), "TimeData must not have padding bytes, see `FixedLayout`");
+        debug_assert!(T::COLUMNS.is_empty() || T::COLUMNS.iter().sum::<usize>() == std::mem::size_of::<T>(), "columns must cover every field, see `Columnar`");
+
+        let (layout, file_size) = column_layout::<T, K>(data.len());
+        let mut payload = Vec::with_capacity(file_size - CHUNK_HEADER_SIZE);
+
+        if layout.is_empty() {
+            payload.extend_from_slice(Self::as_bytes(data));
+        }
+
+        for (i, &(offset, _)) in layout.iter().enumerate() {
+            payload.resize(offset - CHUNK_HEADER_SIZE, 0); //Padding
+
+            if i == 0 {
+                let times: Vec<K> = data.iter().map(|entry| entry.time).collect();
+                payload.extend_from_slice(Self::as_bytes(&times));
+            } else {
+                T::write_column(data, i - 1, &mut payload);
+            }
+        }
+
+        debug_assert_eq!(CHUNK_HEADER_SIZE + payload.len(), file_size);
+
+        let header = ChunkHeader {
+            magic: CHUNK_MAGIC,
+            version: CHUNK_FORMAT_VERSION,
+            type_id: T::TYPE_ID,
+            entry_size: std::mem::size_of::<TimeData<T, K>>() as u32,
+            count: data.len() as u64,
+            checksum: crc32fast::hash(&payload),
+            columns: layout.len() as u32
+        };
+
+        let mut ret = bincode::serialize(&header)?;
+        debug_assert_eq!(ret.len(), CHUNK_HEADER_SIZE);
+
+        ret.extend_from_slice(&payload);
+        Ok(ret)
+    }
+
+    ///Maps the chunk file at `path`, which should hold `count` entries, and
+    ///checks its header. Entries are checked against `checksum` right away if
+    ///`check_now`, which requires reading the whole file, or else on first
+    ///access (see `MappedFile`).
+    fn map(path: &Path, count: usize, checksum: u32, check_now: bool) -> Result<ChunkData<T, K>, ChunkLoadError> {
+        let file = fs::File::open(path).map_err(ChunkLoadError::FileOpenError)?;
+
+        //Mapping a file is unsafe because Rust assumes the memory behind the returned
+        //slice never changes, while the file could be modified or truncated by anyone.
+        //We never write chunk files again once they're mapped (see `MemDB::persist()`),
+        //and deleting them doesn't affect existing mappings, so only other processes
+        //could do that. They have no business in our save directory, and `MappedFile`
+        //catches the damage they'd do before we read any of it, unless they race
+        //with a query.
+        let mapping = unsafe { Mmap::map(&file) }.map_err(ChunkLoadError::MapError)?;
+        let header: ChunkHeader = bincode::deserialize(&mapping[..usize::min(mapping.len(), CHUNK_HEADER_SIZE)]).map_err(ChunkLoadError::DeserializeError)?;
+
+        if header.magic != CHUNK_MAGIC {
+            return Err(ChunkLoadError::BadMagic);
+        }
+
+        if header.version != CHUNK_FORMAT_VERSION {
+            return Err(ChunkLoadError::UnsupportedVersion(header.version));
+        }
+
+        let (layout, file_size) = column_layout::<T, K>(count);
+
+        if header.type_id != T::TYPE_ID || header.entry_size as usize != std::mem::size_of::<TimeData<T, K>>() || header.columns as usize != layout.len() {
+            return Err(ChunkLoadError::TypeMismatch);
+        }
+
+        if count == 0 || header.count != count as u64 || mapping.len() != file_size {
+            return Err(ChunkLoadError::CountMismatch);
+        }
+
+        if header.checksum != checksum || (check_now && crc32fast::hash(&mapping[CHUNK_HEADER_SIZE..]) != checksum) {
+            return Err(ChunkLoadError::ChecksumMismatch);
+        }
+
+        Ok(ChunkData::Mapped(Arc::new(MappedFile {
+            mapping,
+            file,
+            path: path.to_owned(),
+            checksum,
+            state: AtomicU8::new(if check_now { FILE_CHECKED } else { FILE_UNCHECKED }),
+            error: Mutex::new(None)
+        }), count))
+    }
+}
+
+impl MappedFile {
+    ///Returns the mapping, or `None` if the file is broken
+    fn get(&self) -> Option<&Mmap> {
+        let state = self.state.load(Ordering::Acquire);
+
+        if state == FILE_BROKEN {
+            return None;
+        }
+
+        let err = match self.file.metadata() {
+            Ok(metadata) if metadata.len() != self.mapping.len() as u64 => ChunkLoadError::SizeChanged(metadata.len()),
+            Err(err)                                                     => ChunkLoadError::FileCheckError(err),
+            Ok(_) if state == FILE_CHECKED                               => return Some(&self.mapping),
+            Ok(_) if crc32fast::hash(&self.mapping[CHUNK_HEADER_SIZE..]) == self.checksum => {
+                self.state.store(FILE_CHECKED, Ordering::Release); //Checking it twice concurrently does no harm
+                return Some(&self.mapping);
+            },
+            Ok(_) => ChunkLoadError::ChecksumMismatch
+        };
+
+        let mut error = self.error.lock().unwrap();
+
+        if error.is_none() {
+            error!("Chunk file {} is broken: {:?}. Its entries will be missing", self.path.display(), err);
+            *error = Some(format!("{:?}", err));
+        }
+
+        self.state.store(FILE_BROKEN, Ordering::Release);
+        None
+    }
+
+    ///Returns why the file is broken, if it is
+    fn error(&self) -> Option<String> {
+        self.error.lock().unwrap().clone()
+    }
+}
+
+impl Prefetcher {
+    fn new(name: &str) -> Self {
+        let (sender, receiver) = mpsc::sync_channel::<Arc<MappedFile>>(PREFETCH_QUEUE_SIZE);
+
+        let spawned = std::thread::Builder::new().name(format!("{}_prefetcher", name)).spawn(move || {
+            for file in receiver {
+                let checked = file.state.load(Ordering::Acquire) == FILE_CHECKED;
+
+                //Checking the checksum of a file reads all of it, which pages it in as well
+                if let Some(mapping) = file.get().filter(|_| checked) {
+                    //Reading one byte per page is enough to get the whole page in the page cache
+                    for i in (0..mapping.len()).step_by(PAGE_SIZE) {
+                        unsafe { std::ptr::read_volatile(mapping.as_ptr().add(i)); }
+                    }
+                }
+            }
+        });
+
+        if let Err(err) = spawned {
+            //Sending will simply fail, queries will page chunks in themselves
+            warn!("Could not start prefetcher of {}: {}", name, err);
+        }
+
+        Self {
+            sender: Mutex::new(sender)
+        }
+    }
+}
+
+///Returns the offset and size of each column section of a chunk file holding
+///`count` entries of `T`, along with the size of the whole file. If `T` has
+///columns (see `Columnar`), the first section holds the times of the entries,
+///and the others `Columnar::COLUMNS`. Otherwise, there are no sections: the
+///entries are stored as-is after the header. Sections are 8-byte aligned, so
+///that columns can be used in place.
+fn column_layout<T: Columnar, K>(count: usize) -> (Vec<(usize, usize)>, usize) {
+    if T::COLUMNS.is_empty() {
+        return (Vec::new(), CHUNK_HEADER_SIZE + count * std::mem::size_of::<TimeData<T, K>>());
+    }
+
+    let mut offset = CHUNK_HEADER_SIZE;
+    let widths = std::iter::once(std::mem::size_of::<K>()).chain(T::COLUMNS.iter().cloned());
+
+    let layout = widths.map(|width| {
+        offset = (offset + 7) & !7;
+        let section = (offset, width * count);
+
+        offset += width * count;
+        section
+    }).collect();
+
+    (layout, offset)
+}
+
+impl<T: FixedLayout + Columnar, K: TimeKey> ChunkData<T, K> {
+    ///Returns the entries of the chunk, see `Rows`. Broken chunk files have none.
+    fn rows(&self) -> Rows<T, K> {
+        match self {
+            ChunkData::Heap(vec) => Rows::Slice(vec.as_slice()),
+            ChunkData::Dropped => Rows::Slice(&[]),
+            ChunkData::Mapped(file, count) if T::COLUMNS.is_empty() => match file.get() {
+                //Safe because the header was checked in `Chunk::map()`, the mapping is page-aligned,
+                //CHUNK_HEADER_SIZE keeps the entries aligned, and T is plain data
+                Some(mapping) => Rows::Slice(unsafe { std::slice::from_raw_parts(mapping.as_ptr().add(CHUNK_HEADER_SIZE) as *const TimeData<T, K>, *count) }),
+                None          => Rows::Slice(&[])
+            },
+            ChunkData::Mapped(_, _) => match self.columns() {
+                Some(view) => Rows::Columns(view),
+                None       => Rows::Slice(&[])
+            }
+        }
+    }
+
+    ///Returns all the entries of the chunk, rebuilding them if needed, see `rows()`
+    fn entries(&self) -> Cow<[TimeData<T, K>]> {
+        let rows = self.rows();
+        rows.get(0, rows.len())
+    }
+
+    ///Returns the columns of chunk files that store them, see `Columnar`.
+    ///Broken chunk files have none.
+    fn columns(&self) -> Option<ColumnView<K>> {
+        match self {
+            ChunkData::Mapped(file, count) if !T::COLUMNS.is_empty() => {
+                let mapping = file.get()?;
+                let (layout, _) = column_layout::<T, K>(*count);
+                let sections: Vec<&[u8]> = layout.iter().map(|&(offset, size)| &mapping[offset..offset + size]).collect();
+
+                //Safe because the section is 8-byte aligned and was written from Ks
+                let times = unsafe { std::slice::from_raw_parts(sections[0].as_ptr() as *const K, *count) };
+                Some(ColumnView { times, columns: sections[1..].to_vec() })
+            },
+            _ => None
+        }
+    }
+}
+
+impl<'a, T: FixedLayout + Columnar, K: TimeKey> Rows<'a, T, K> {
+    fn len(&self) -> usize {
+        match self {
+            Rows::Slice(entries) => entries.len(),
+            Rows::Columns(view)  => view.times.len()
+        }
+    }
+
+    ///Returns the index of the first entry that isn't older than `t`
+    fn partition(&self, t: K) -> usize {
+        match self {
+            Rows::Slice(entries) => entries.binary_search_by(|e| if e.time < t { std::cmp::Ordering::Less } else { std::cmp::Ordering::Greater }).unwrap_err(),
+            Rows::Columns(view)  => view.times.binary_search_by(|&x| if x < t { std::cmp::Ordering::Less } else { std::cmp::Ordering::Greater }).unwrap_err()
+        }
+    }
+
+    ///Returns entries [a; b), which are only rebuilt if the chunk stores columns
+    fn get(&self, a: usize, b: usize) -> Cow<'a, [TimeData<T, K>]> {
+        match *self {
+            Rows::Slice(entries)    => Cow::Borrowed(&entries[a..b]),
+            Rows::Columns(ref view) => {
+                let mut ret = Vec::with_capacity(b - a);
+                T::read_rows(&view.slice(a, b), &mut ret);
+
+                Cow::Owned(ret)
+            }
+        }
+    }
+}
+
+impl<T, K> CurrentChunk<T, K> {
+    fn with_capacity(capacity: usize) -> Self {
+        Self {
+            entries: (0..capacity).map(|_| UnsafeCell::new(MaybeUninit::uninit())).collect::<Vec<_>>().into_boxed_slice(),
+            len: AtomicUsize::new(0)
+        }
+    }
+
+    ///Capacity of the chunk that follows one of `count` entries: streams tend to keep
+    ///their rate, and it grows anyway if they don't
+    fn next_capacity(count: usize) -> usize {
+        usize::min(usize::max(count.next_power_of_two(), MIN_CURRENT_CAPACITY), SWAP_THRESHOLD)
+    }
+
+    #[inline]
+    fn capacity(&self) -> usize {
+        self.entries.len()
+    }
+
+    ///Writes `entry` at `index`, without publishing it
+    ///
+    ///Unsafe because only one thread may write at a time, and
+    ///because `index` must be past the published entries and
+    ///within the chunk.
+    unsafe fn write(&self, index: usize, entry: TimeData<T, K>) {
+        (*self.entries[index].get()).write(entry);
+    }
+
+    ///Makes the first `len` entries visible to readers
+    fn publish(&self, len: usize) {
+        self.len.store(len, Ordering::Release);
+    }
+
+    fn as_slice(&self) -> &[TimeData<T, K>] {
+        let len = self.len.load(Ordering::Acquire);
+
+        //Safe because the first `len` entries are initialized and will never be written again
+        unsafe { std::slice::from_raw_parts(self.entries.as_ptr() as *const TimeData<T, K>, len) }
+    }
+}
+
+//Entries are written by a single thread and only read once published
+unsafe impl<T: Send, K: Send> Send for CurrentChunk<T, K> {}
+unsafe impl<T: Send + Sync, K: Send + Sync> Sync for CurrentChunk<T, K> {}
+
+impl<T: Summarize<K> + Indexed, K: TimeKey> Contents<T, K> {
+    fn chunk_path(&self, cid: usize) -> PathBuf {
+        self.save_path.join(cid.to_string())
+    }
+
+    ///Time of the oldest entry that wasn't dropped
+    fn min_time(&self) -> K {
+        let snapshot = self.snapshot.read().unwrap().clone();
+
+        match snapshot.old_chunks.get(snapshot.first_live) {
+            Some(chunk) => chunk.min,
+            None => snapshot.current_chunk.as_slice().first().map(|entry| entry.time).unwrap_or_else(|| K::from_bits(self.max.load(Ordering::Acquire)))
+        }
+    }
+}
+
+impl<T: Summarize<K> + Indexed, K: TimeKey> Clone for Snapshot<T, K> {
+    fn clone(&self) -> Self {
+        Self {
+            first_live: self.first_live,
+            old_chunks: self.old_chunks.clone(),
+            current_chunk: self.current_chunk.clone()
+        }
+    }
+}
+
+impl<T: Summarize<K> + Indexed, K: TimeKey> Snapshot<T, K> {
+    ///Calls `f` with every key of the published entries that weren't dropped,
+    ///see `MemDB::for_each_key()`
+    fn for_each_key<F: FnMut(T::Key)>(&self, f: &mut F) {
+        for chunk in &self.old_chunks[self.first_live..] {
+            chunk.index.keys().copied().for_each(&mut *f);
+        }
+
+        for entry in self.current_chunk.as_slice() {
+            entry.data.index_keys(&mut *f);
+        }
+    }
+}
+
+///MemDB is just a fancy name for "huge vec". It can store a lot
+///of data indexed by a "time" field (of type `K`, see `TimeKey`).
+///It can then perform queries based on this field.
+///
+///Data is inserted but never modified. Internally, data is split
+///into chunks, each one containing a maximum of `SWAP_THRESHOLD`
+///entries. Once full (or earlier, see `set_seal_span()` and
+///`set_seal_idle()`), chunks are written to disk (inside
+///`save_path`) and memory-mapped, so that the OS page cache
+///decides which parts of them stay in RAM. The only way to delete
+///data is to drop the oldest chunks as a whole (see
+///`drop_chunks_before()`), which retention policies rely on.
+///
+///Pushing never blocks on queries: entries are appended to a
+///chunk where they never move and published atomically, and sealed chunks
+///are immutable. Queries work on a snapshot of the chunk list.
+///
+///Another restriction (in addition to the "append-only"
+///constraint) is that the `time` field inside the `TimeData`
+///struct can only be increasing. This enables fast queries
+///through the help of binary search algorithms. To tolerate
+///entries that arrive slightly out of order, pushed entries are
+///held back and sorted for `reorder_window` (on the data's
+///timeline) before being published.
+impl<T: FixedLayout + Summarize<K> + Indexed + Columnar, K: TimeKey> MemDB<T, K> {
+    ///Creates a MemDB instance
+    ///
+    ///`save_path` must point to an empty directory and this
+    ///instance of MemDB should be the only entity able to
+    ///write to this folder. It is the user's responsibility to
+    ///erase all files contained in this folder before calling
+    ///this function.
+    pub fn new(context: &MemDbContext, name: String, save_path: PathBuf) -> Self {
+        Self::with_chunks(context, name, save_path, Vec::new(), Vec::new())
+    }
+
+    ///Rebuilds a MemDB from the manifest and chunk files left in `save_path` by a
+    ///previous instance, e.g. one that crashed. Entries that weren't part of a
+    ///sealed chunk are lost. Missing or corrupted chunks are replaced by empty
+    ///placeholders, so that the chunks after them keep their indices (and thus
+    ///entry IDs), and are reported by `Accessor::get_health()`. Their files are
+    ///left untouched, and their indices are never given to new chunks. The same
+    ///goes for chunks the manifest doesn't list, and for chunk files left after
+    ///the last chunk it lists.
+    ///
+    ///Same requirements as `new()`, except that `save_path` should contain the
+    ///files of a previous instance instead of being empty.
+    pub fn recover(context: &MemDbContext, name: String, save_path: PathBuf) -> Result<Self, RecoveryError> {
+        let file = fs::File::open(save_path.join(MANIFEST_FILE)).map_err(RecoveryError::ManifestOpenError)?;
+        let manifest: Vec<ManifestEntry<K>> = bincode::deserialize_from(std::io::BufReader::new(file)).map_err(RecoveryError::ManifestDeserializeError)?;
+        let total = manifest.len();
+        let mut old_chunks = Vec::with_capacity(total);
+        let mut recovered = Vec::with_capacity(total);
+        let mut failures = Vec::new();
+
+        for entry in manifest {
+            if entry.id < old_chunks.len() {
+                error!("Manifest of {} lists chunk {} twice or out of order. Ignoring it", name, entry.id);
+                continue;
+            }
+
+            while entry.id > old_chunks.len() {
+                let t = old_chunks.last().map(|chunk: &Arc<Chunk<T, K>>| chunk.max).unwrap_or(entry.min);
+                Self::recover_missing(&name, t, "not listed in the manifest", &mut old_chunks, &mut recovered, &mut failures);
+            }
+
+            let result = if entry.dropped {
+                Ok(None)
+            } else {
+                Chunk::map(&save_path.join(entry.id.to_string()), entry.count, entry.checksum, true).map(Some)
+            };
+
+            match result {
+                Ok(Some(data)) => {
+                    old_chunks.push(Arc::new(Chunk::new(data)));
+                    recovered.push(entry);
+                },
+                Ok(None) => {
+                    old_chunks.push(Arc::new(Chunk::dropped(entry.min, entry.max)));
+                    recovered.push(entry);
+                },
+                Err(err) => {
+                    error!("Could not recover chunk {} of {}: {:?}. Its entries will be missing", entry.id, name, err);
+
+                    failures.push(ChunkFailure {
+                        chunk: entry.id,
+                        min: entry.min,
+                        max: entry.max,
+                        count: entry.count,
+                        error: format!("{:?}", err)
+                    });
+
+                    //Still listed in the manifest, so that the next recovery reports it as well
+                    old_chunks.push(Arc::new(Chunk::dropped(entry.min, entry.max)));
+                    recovered.push(entry);
+                }
+            }
+        }
+
+        //Written before the manifest was updated: their indices must not be reused
+        while save_path.join(old_chunks.len().to_string()).exists() {
+            let t = old_chunks.last().map(|chunk| chunk.max).unwrap_or_default();
+            Self::recover_missing(&name, t, "written after the manifest was last updated", &mut old_chunks, &mut recovered, &mut failures);
+        }
+
+        info!("Recovered {} chunks out of {} for {}", old_chunks.len() - failures.len(), old_chunks.len(), name);
+
+        let ret = Self::with_chunks(context, name, save_path, old_chunks, recovered);
+        ret.contents.failed_chunks.lock().unwrap().extend(failures);
+
+        if let Err(err) = ret.write_manifest() {
+            error!("Could not update manifest of {}: {:?}", ret.contents.name, err);
+        }
+
+        Ok(ret)
+    }
+
+    ///Adds a placeholder for a chunk that can't be recovered because of `reason`, at time `t`.
+    ///It is listed in the manifest, so that its index isn't reused and the next recovery reports it too.
+    fn recover_missing(name: &str, t: K, reason: &str, old_chunks: &mut Vec<Arc<Chunk<T, K>>>, manifest: &mut Vec<ManifestEntry<K>>, failures: &mut Vec<ChunkFailure<K>>) {
+        let id = old_chunks.len();
+        error!("Chunk {} of {} is {}. Its entries will be missing", id, name, reason);
+
+        failures.push(ChunkFailure {
+            chunk: id,
+            min: t,
+            max: t,
+            count: 0,
+            error: reason.to_string()
+        });
+
+        old_chunks.push(Arc::new(Chunk::dropped(t, t)));
+        manifest.push(ManifestEntry {
+            id,
+            min: t,
+            max: t,
+            count: 0, //Fails to map, since chunk files are never empty
+            checksum: 0,
+            dropped: false
+        });
+    }
+
+    fn with_chunks(context: &MemDbContext, name: String, save_path: PathBuf, old_chunks: Vec<Arc<Chunk<T, K>>>, manifest: Vec<ManifestEntry<K>>) -> Self {
+        let current_chunk = Arc::new(CurrentChunk::with_capacity(CurrentChunk::<T, K>::next_capacity(old_chunks.last().map(|chunk| chunk.count).unwrap_or(0))));
+        let max = old_chunks.last().map(|chunk| chunk.max).unwrap_or_default();
+        let first_live = manifest.iter().position(|entry| !entry.dropped).unwrap_or(manifest.len()); //Unrecoverable chunks can still be dropped
+        let stored_bytes = old_chunks.iter().map(|chunk| chunk.size()).sum();
+
+        Self {
+            context: context.clone(),
+            contents: Arc::new(Contents {
+                snapshot: RwLock::new(Snapshot {
+                    first_live,
+                    old_chunks: Arc::new(old_chunks),
+                    current_chunk: current_chunk.clone()
+                }),
+
+                max: AtomicU64::new(max.to_bits()),
+                late: AtomicU64::new(0),
+                failed_chunks: Mutex::new(Vec::new()),
+                prefetcher: Prefetcher::new(&name),
+                save_path,
+                name
+            }),
+
+            manifest,
+            current_chunk,
+            max,
+            pending: Vec::new(),
+            reorder_window: K::default(),
+            stored_bytes,
+            current_min: K::default(),
+            last_push: context.now(),
+            seal_span: None,
+            seal_idle: None,
+            late_warning: None,
+            unreported_late: 0
+        }
+    }
+
+    ///Returns false if an entry at time `t` would be counted as late (and dropped)
+    ///by `extend()`, which only changes once `extend()` or `flush()` is called
+    pub fn accepts(&self, t: K) -> bool {
+        t >= self.max
+    }
+
+    ///Sets for how long (on the data's timeline) entries are held back so that entries
+    ///arriving out of order can be sorted before being published. Entries
+    ///older than the last published one are counted as late and dropped.
+    pub fn set_reorder_window(&mut self, window: K) {
+        self.reorder_window = window;
+    }
+
+    ///Pushes a batch of entries, in any order. Entries are published (and
+    ///the max time updated) once the reorder window has passed, i.e. once
+    ///an entry at least `reorder_window` newer has been pushed.
+    ///Also see `flush()`.
+    pub fn extend(&mut self, entries: &[TimeData<T, K>]) {
+        if entries.is_empty() {
+            return;
+        }
+
+        self.last_push = self.context.now();
+
+        let mut batch: Vec<TimeData<T, K>> = entries.iter().filter(|e| self.accepts(e.time)).cloned().collect();
+        let late = (entries.len() - batch.len()) as u64;
+
+        //Stable, so that entries that share a time stay in the order they were pushed in
+        batch.sort_by_key(|e| e.time);
+
+        let in_order = match (self.pending.last(), batch.first()) {
+            (Some(last), Some(first)) => last.time <= first.time,
+            _                         => true
+        };
+
+        if in_order {
+            self.pending.extend_from_slice(&batch); //Entries are mostly sorted already, so this is the usual case
+        } else {
+            let pending = std::mem::take(&mut self.pending);
+            let mut batch = batch.into_iter().peekable();
+
+            self.pending.reserve(pending.len() + batch.len());
+
+            for entry in pending {
+                while batch.peek().map(|e| e.time < entry.time).unwrap_or(false) {
+                    self.pending.push(batch.next().unwrap());
+                }
+
+                self.pending.push(entry);
+            }
+
+            self.pending.extend(batch);
+        }
+
+        if late > 0 {
+            self.contents.late.fetch_add(late, Ordering::Relaxed);
+            self.unreported_late += late;
+        }
+
+        //Warning for every late batch could flood the log, they're counted by `Accessor::get_late_count()` anyway
+        if self.unreported_late > 0 && self.late_warning.map(|t| self.last_push - t >= LATE_WARNING_INTERVAL).unwrap_or(true) {
+            warn!("{} entries of {} arrived after the reorder window and were dropped ({} in total)", self.unreported_late, self.contents.name, self.contents.late.load(Ordering::Relaxed));
+
+            self.late_warning = Some(self.last_push);
+            self.unreported_late = 0;
+        }
+
+        if let Some(newest) = self.pending.last().map(|e| e.time) {
+            let count = self.pending.iter().position(|e| e.time.saturating_add(self.reorder_window) > newest).unwrap_or(self.pending.len());
+
+            self.publish_pending(count);
+        }
+    }
+
+    ///Seals the current chunk once its entries span more than `span`
+    ///(on the data's timeline), even if it isn't full. This keeps low-rate
+    ///streams from staying in the current chunk for hours.
+    pub fn set_seal_span(&mut self, span: Option<K>) {
+        self.seal_span = span;
+    }
+
+    ///Makes `seal_if_idle()` seal the current chunk once nothing has been
+    ///pushed for `idle`, in real time (as told by the `Clock` of the context).
+    pub fn set_seal_idle(&mut self, idle: Option<Duration>) {
+        self.seal_idle = idle;
+    }
+
+    ///Publishes the pending entries and seals the current chunk if nothing was
+    ///pushed for longer than the delay set by `set_seal_idle()`. Meant to be
+    ///called periodically, whether entries are coming in or not. Returns true
+    ///if a chunk was sealed.
+    pub fn seal_if_idle(&mut self) -> bool {
+        let idle = match self.seal_idle {
+            Some(idle) => idle,
+            None       => return false
+        };
+
+        if self.context.now() - self.last_push < idle {
+            return false;
+        }
+
+        self.flush();
+
+        if self.current_chunk.as_slice().is_empty() {
+            return false;
+        }
+
+        debug!("Sealing current chunk of {} after {:?} without any entry", self.contents.name, idle);
+        self.seal();
+        true
+    }
+
+    ///Publishes all pending entries, regardless of the reorder window.
+    ///Meant to be called when no new entries are coming in.
+    pub fn flush(&mut self) {
+        self.publish_pending(self.pending.len());
+    }
+
+    ///Moves the first `count` pending entries into the current chunk
+    fn publish_pending(&mut self, count: usize) {
+        if count == 0 {
+            return;
+        }
+
+        let initial_len = self.current_chunk.len.load(Ordering::Relaxed); //We're the only writer
+        let mut len = initial_len;
+        let mut pending = std::mem::take(&mut self.pending);
+
+        for entry in pending.drain(..count) {
+            if len > 0 && self.seal_span.map(|span| entry.time.saturating_sub(self.current_min) > span).unwrap_or(false) {
+                self.current_chunk.publish(len);
+                self.seal();
+                len = 0;
+            }
+
+            if len == 0 {
+                self.current_min = entry.time;
+            }
+
+            if len >= self.current_chunk.capacity() {
+                self.current_chunk.publish(len);
+                self.grow_current_chunk();
+            }
+
+            self.max = entry.time;
+            unsafe { self.current_chunk.write(len, entry); } //Safe because we're the only writer and we grow or seal full chunks right away
+            len += 1;
+
+            if len >= SWAP_THRESHOLD {
+                self.current_chunk.publish(len);
+                self.seal();
+                len = 0;
+            }
+        }
+
+        self.pending = pending;
+        self.current_chunk.publish(len);
+        self.contents.max.store(self.max.to_bits(), Ordering::Release);
+
+        if len / 1000 != initial_len / 1000 {
+            debug!("Current chunk of {} contains {} elements", self.contents.name, len);
+        }
+    }
+
+    ///Turns the current chunk into an immutable one, persists it and starts
+    ///a new one. Readers only block this for as long as it takes to clone a
+    ///snapshot.
+    fn seal(&mut self) {
+        let sealed = self.current_chunk.clone();
+        let entries = sealed.as_slice();
+
+        if entries.is_empty() {
+            return;
+        }
+
+        let index = self.contents.snapshot.read().unwrap().old_chunks.len(); //Only we can change it
+
+        //Entries are still readable from the current chunk in the meantime
+        let data = match self.persist(index, entries) {
+            Ok(data) => data,
+            Err(err) => {
+                error!("Could not persist chunk {} of {}: {:?}. It will stay in RAM.", index, self.contents.name, err);
+
+                self.contents.failed_chunks.lock().unwrap().push(ChunkFailure {
+                    chunk: index,
+                    min: entries[0].time,
+                    max: entries[entries.len() - 1].time,
+                    count: entries.len(),
+                    error: format!("{:?}", err)
+                });
+
+                ChunkData::Heap(entries.to_vec())
+            }
+        };
+
+        //No need to read back what we just wrote
+        let mut chunk = Chunk::with_entries(entries);
+        chunk.data = data;
+
+        let current_chunk = Arc::new(CurrentChunk::with_capacity(CurrentChunk::<T, K>::next_capacity(entries.len())));
+        self.stored_bytes += chunk.size();
+
+        let mut snapshot = self.contents.snapshot.write().unwrap();
+        let mut old_chunks = Vec::clone(&snapshot.old_chunks);
+
+        old_chunks.push(Arc::new(chunk));
+        snapshot.old_chunks = Arc::new(old_chunks);
+        snapshot.current_chunk = current_chunk.clone();
+        drop(snapshot);
+
+        self.current_chunk = current_chunk;
+    }
+
+    ///Replaces the current chunk, which is full, by a copy twice as big. Readers
+    ///that still hold the old one can keep reading it: entries are only written
+    ///past what it published.
+    fn grow_current_chunk(&mut self) {
+        let entries = self.current_chunk.as_slice();
+        let current_chunk = CurrentChunk::with_capacity(usize::min(2 * self.current_chunk.capacity(), SWAP_THRESHOLD));
+
+        for (i, entry) in entries.iter().enumerate() {
+            unsafe { current_chunk.write(i, *entry); } //Safe because nobody else can see it yet
+        }
+
+        current_chunk.publish(entries.len());
+
+        let current_chunk = Arc::new(current_chunk);
+        self.contents.snapshot.write().unwrap().current_chunk = current_chunk.clone();
+        self.current_chunk = current_chunk;
+    }
+
+    ///Writes a freshly sealed chunk to disk, adds it to the manifest and maps
+    ///it. The manifest is replaced atomically, so that it always describes
+    ///chunks that were entirely written.
+    fn persist(&mut self, index: usize, entries: &[TimeData<T, K>]) -> Result<ChunkData<T, K>, ChunkSaveError> {
+        let path = self.contents.chunk_path(index);
+        let bytes = Chunk::encode(entries).map_err(ChunkSaveError::SerializeError)?;
+        write_synced(&path, &bytes)?;
+
+        let checksum = crc32fast::hash(&bytes[CHUNK_HEADER_SIZE..]);
+        let data = Chunk::map(&path, entries.len(), checksum, false).map_err(ChunkSaveError::MapError)?;
+
+        self.manifest.push(ManifestEntry {
+            id: index,
+            min: entries[0].time,
+            max: entries[entries.len() - 1].time,
+            count: entries.len(),
+            checksum,
+            dropped: false
+        });
+
+        self.write_manifest()?;
+        Ok(data)
+    }
+
+    ///Replaces the manifest file atomically
+    fn write_manifest(&self) -> Result<(), ChunkSaveError> {
+        let tmp_path = self.contents.save_path.join(MANIFEST_TMP_FILE);
+        let bytes = bincode::serialize(&self.manifest).map_err(ChunkSaveError::SerializeError)?;
+
+        write_synced(&tmp_path, &bytes)?;
+        fs::rename(&tmp_path, self.contents.save_path.join(MANIFEST_FILE)).map_err(ChunkSaveError::ManifestRenameError)
+    }
+
+    ///Drops the oldest sealed chunks whose entries are all older than `t`,
+    ///and returns how many were dropped. See `drop_oldest_chunks()`.
+    pub fn drop_chunks_before(&mut self, t: K) -> usize {
+        let snapshot = self.contents.snapshot.read().unwrap().clone();
+        let count = snapshot.old_chunks[snapshot.first_live..].iter().take_while(|chunk| chunk.max < t).count();
+
+        self.drop_oldest_chunks(count)
+    }
+
+    ///Drops the `count` oldest sealed chunks that weren't dropped yet, along with
+    ///their files. Dropped chunks keep their index, so the entry IDs of other
+    ///chunks don't change; queries simply won't return anything from them.
+    ///Returns how many chunks were actually dropped.
+    pub fn drop_oldest_chunks(&mut self, count: usize) -> usize {
+        let mut snapshot = self.contents.snapshot.write().unwrap();
+        let first = snapshot.first_live;
+        let end = usize::min(first + count, snapshot.old_chunks.len());
+
+        if end <= first {
+            return 0;
+        }
+
+        let mut old_chunks = Vec::clone(&snapshot.old_chunks);
+
+        for i in first..end {
+            self.stored_bytes -= old_chunks[i].size();
+            old_chunks[i] = Arc::new(Chunk::dropped(old_chunks[i].min, old_chunks[i].max));
+        }
+
+        snapshot.first_live = end;
+        snapshot.old_chunks = Arc::new(old_chunks);
+        drop(snapshot);
+
+        //Mappings held by running queries remain valid after the files are removed
+        for entry in self.manifest.iter_mut().filter(|entry| entry.id >= first && entry.id < end) {
+            entry.dropped = true;
+        }
+
+        if let Err(err) = self.write_manifest() {
+            error!("Could not update manifest of {}: {:?}", self.contents.name, err);
+        }
+
+        for i in first..end {
+            if let Err(err) = fs::remove_file(self.contents.chunk_path(i)) {
+                if err.kind() != std::io::ErrorKind::NotFound {
+                    warn!("Could not remove dropped chunk {} of {}: {}", i, self.contents.name, err);
+                }
+            }
+        }
+
+        self.contents.failed_chunks.lock().unwrap().retain(|f| f.chunk >= end);
+        debug!("Dropped {} chunks of {}", end - first, self.contents.name);
+
+        end - first
+    }
+
+    ///Returns the time of the last entry of the oldest chunk that could be dropped
+    pub fn get_oldest_chunk_end(&self) -> Option<K> {
+        let snapshot = self.contents.snapshot.read().unwrap();
+        snapshot.old_chunks.get(snapshot.first_live).map(|chunk| chunk.max)
+    }
+
+    ///Returns how many bytes the sealed chunks that weren't dropped use, i.e. the size
+    ///of their files, or what it would be for those that stayed in RAM. The current
+    ///chunk isn't counted.
+    pub fn get_stored_bytes(&self) -> u64 {
+        self.stored_bytes
+    }
+
+    pub fn get_max_time(&self) -> K {
+        self.max
+    }
+
+    pub fn get_min_time(&self) -> K {
+        self.contents.min_time()
+    }
+
+    ///Entries that were pushed but aren't published yet, see `set_reorder_window()`
+    pub fn get_pending(&self) -> &[TimeData<T, K>] {
+        &self.pending
+    }
+
+    ///Calls `f` with every key (see `Indexed`) of the entries that weren't dropped,
+    ///pending ones included. Keys of sealed chunks are read from their index and
+    ///may be visited several times.
+    pub fn for_each_key<F: FnMut(T::Key)>(&self, mut f: F) {
+        let snapshot = self.contents.snapshot.read().unwrap().clone();
+        snapshot.for_each_key(&mut f);
+
+        for entry in &self.pending {
+            entry.data.index_keys(&mut f);
+        }
+    }
+
+    pub fn new_accessor(&self) -> Accessor<T, K> {
+        Accessor {
+            contents: self.contents.clone(),
+            last_range: Mutex::new(None)
+        }
+    }
+}
+
+impl<T: ShouldStopQuery<K> + FixedLayout + Summarize<K> + Indexed + Columnar, K: TimeKey> Accessor<T, K> {
+    fn snapshot(&self) -> Snapshot<T, K> {
+        self.contents.snapshot.read().unwrap().clone()
+    }
+
+    ///Returns the index of the first chunk that may contain entries newer than `t`.
+    ///If it is `old_chunks.len()`, then only the current chunk may. Chunks dropped
+    ///by the retention policy are never returned.
+    fn first_chunk(snapshot: &Snapshot<T, K>, t: K) -> usize {
+        let old_chunks = &snapshot.old_chunks[snapshot.first_live..];
+
+        snapshot.first_live + if old_chunks.is_empty() || t <= old_chunks[0].max {
+            0
+        } else if t > old_chunks[old_chunks.len() - 1].max {
+            old_chunks.len()
+        } else {
+            Self::binary_search_chunk(old_chunks, t)
+        }
+    }
+
+    ///Returns i such that data[i].time >= min and data[i - 1].time < min
+    ///Careful, as it assumes that data[0].time < min <= data[data.len() - 1].time
+    ///(it panics or never returns otherwise)
+    fn binary_search(data: &[TimeData<T, K>], min: K) -> usize {
+        debug_assert!(data.first().map(|e| e.time < min).unwrap_or(false) && data[data.len() - 1].time >= min);
+
+        let mut a = 0;
+        let mut b = data.len();
+
+        loop {
+            let half = (a + b) >> 1;
+
+            if data[half].time >= min {
+                if data[half - 1].time < min {
+                    return half;
+                }
+
+                b = half;
+            } else {
+                a = half;
+            }
+        }
+    }
+
+    ///Returns i such that data[i].max >= min and data[i - 1].max < min
+    ///Careful, as it assumes that data[0].max < min <= data[data.len() - 1].max
+    ///(it panics or never returns otherwise)
+    fn binary_search_chunk(data: &[Arc<Chunk<T, K>>], min: K) -> usize {
+        debug_assert!(data.first().map(|c| c.max < min).unwrap_or(false) && data[data.len() - 1].max >= min);
+
+        let mut a = 0;
+        let mut b = data.len();
+
+        loop {
+            let half = (a + b) >> 1;
+
+            if data[half].max >= min {
+                if data[half - 1].max < min {
+                    return half;
+                }
+
+                b = half;
+            } else {
+                a = half;
+            }
+        }
+    }
+
+    ///Records that [min; max] is being queried and pages in, from a background
+    ///thread, the chunks a client scrolling towards `hint` will need next, i.e.
+    ///those of the adjacent range of the same width. Without `hint`, the
+    ///direction is guessed from the range previously recorded by this accessor,
+    ///and nothing happens if it can't be (e.g. when zooming). Since accessors
+    ///may be shared by several clients, those that know where they're heading
+    ///should send the hint. Requests are dropped if the prefetcher lags behind.
+    pub fn prefetch(&self, min: K, max: K, hint: Option<PrefetchHint>) {
+        let last = self.last_range.lock().unwrap().replace((min, max));
+
+        let direction = hint.or_else(|| match last {
+            Some((last_min, last_max)) if min > last_min && max > last_max => Some(PrefetchHint::Right),
+            Some((last_min, last_max)) if min < last_min && max < last_max => Some(PrefetchHint::Left),
+            _ => None
+        });
+
+        let width = max.saturating_sub(min);
+
+        let (from, to) = match direction {
+            Some(PrefetchHint::Left)  => (min.saturating_sub(width), min),
+            Some(PrefetchHint::Right) => (max, max.saturating_add(width)),
+            None                      => return
+        };
+
+        let snapshot = self.snapshot();
+        let first = Self::first_chunk(&snapshot, from);
+        let sender = self.contents.prefetcher.sender.lock().unwrap();
+
+        for chunk in snapshot.old_chunks[first..].iter().take_while(|chunk| chunk.min <= to).take(PREFETCH_MAX_CHUNKS) {
+            if let ChunkData::Mapped(file, _) = &chunk.data {
+                //Either the queue is full or the thread is gone: queries will page the rest in themselves
+                if sender.try_send(file.clone()).is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    ///Returns a bitmask of the blocks of chunk `cid` that contain any of `keys`,
+    ///along with the time of its first entry, or `None` if `cid` is the current
+    ///chunk, which isn't indexed.
+    fn chunk_mask(snapshot: &Snapshot<T, K>, cid: usize, keys: &[T::Key]) -> Option<(u64, K)> {
+        snapshot.old_chunks.get(cid).map(|chunk| {
+            let mask = keys.iter().filter_map(|k| chunk.index.get(k)).fold(0, |acc, &bits| acc | bits);
+            (mask, chunk.min)
+        })
+    }
+
+    ///Calls `func` with the entries of chunk `cid` of `snapshot`, which is the
+    ///current chunk if `cid` is past the sealed ones. Queries take a single
+    ///snapshot and pass it to every helper, so that they see a consistent
+    ///chunk list and don't pay for the lock more than once.
+    fn with_chunk<U, Func: FnOnce(&Rows<T, K>) -> U>(snapshot: &Snapshot<T, K>, cid: usize, func: Func) -> U {
+        if cid >= snapshot.old_chunks.len() {
+            func(&Rows::Slice(snapshot.current_chunk.as_slice()))
+        } else {
+            func(&snapshot.old_chunks[cid].data.rows())
+        }
+    }
+
+    fn query_left(snapshot: &Snapshot<T, K>, cid: usize, t: K, max: usize, dst: &mut Vec<TimeData<T, K>>) -> usize where T: Copy {
+        Self::with_chunk(snapshot, cid, move |rows| {
+            let end   = rows.partition(t);
+            let cnt   = usize::min(end, max);
+            let start = end - cnt;
+
+            if cnt > 0 {
+                dst.extend(rows.get(start, end).iter().rev());
+            }
+
+            cnt
+        })
+    }
+
+    fn query_right(snapshot: &Snapshot<T, K>, cid: usize, t: K, max: usize, dst: &mut Vec<TimeData<T, K>>) -> usize where T: Copy {
+        Self::with_chunk(snapshot, cid, move |rows| {
+            let start = rows.partition(t);
+            let cnt   = usize::min(rows.len() - start, max);
+            let end   = start + cnt;
+
+            if cnt > 0 {
+                dst.extend_from_slice(&rows.get(start, end));
+            }
+
+            cnt
+        })
+    }
+
+    ///Calls `query` (`query_left()` or `query_right()`) on chunk `cid` within `budget`,
+    ///dropping the entries that don't fit in it. Returns how many entries were kept.
+    fn query_side<Q>(snapshot: &Snapshot<T, K>, query: Q, cid: usize, t: K, max: usize, dst: &mut Vec<TimeData<T, K>>, budget: Option<&Budget>) -> usize
+        where T: Copy, Q: Fn(&Snapshot<T, K>, usize, K, usize, &mut Vec<TimeData<T, K>>) -> usize
+    {
+        let budget = match budget {
+            Some(budget) => budget,
+            None         => return query(snapshot, cid, t, max, dst)
+        };
+
+        if cid < snapshot.old_chunks.len() && !budget.charge_chunk() {
+            return 0;
+        }
+
+        let count = query(snapshot, cid, t, max, dst);
+        let granted = budget.charge_entries(count);
+
+        dst.truncate(dst.len() - (count - granted));
+        granted
+    }
+
+    ///Returns `count` entries around `t`, half of them before it if possible. If `budget`
+    ///is exceeded, only the entries found until then are returned.
+    pub fn query_count<Func: FnMut(&TimeData<T, K>)>(&self, t: K, count: usize, budget: Option<&Budget>, mut callback: Func) where T: Copy {
+        let snapshot = self.snapshot();
+        let chunk_count = snapshot.old_chunks.len();
+        let first_live = snapshot.first_live;
+        let first_chunk = Self::first_chunk(&snapshot, t);
+
+        let exhausted = || budget.map(|b| b.exceeded().is_some()).unwrap_or(false);
+        let mut remaining_left = count / 2;
+        let mut remaining_right = count - remaining_left;
+        let mut left = Vec::with_capacity(remaining_left);
+        let mut right = Vec::with_capacity(remaining_right);
+        let mut cid = first_chunk;
+        let mut left_limit_hit = false;
+
+        if remaining_left > 0 {
+            loop {
+                remaining_left -= Self::query_side(&snapshot, Self::query_left, cid, t, remaining_left, &mut left, budget);
+
+                if remaining_left == 0 || exhausted() {
+                    break;
+                }
+
+                if cid <= first_live {
+                    left_limit_hit = true;
+                    break;
+                }
+
+                cid -= 1;
+            }
+        }
+
+        remaining_right += remaining_left;
+        cid = first_chunk;
+
+        while remaining_right > 0 && cid <= chunk_count && !exhausted() {
+            remaining_right -= Self::query_side(&snapshot, Self::query_right, cid, t, remaining_right, &mut right, budget);
+            cid += 1;
+        }
+
+        if remaining_right > 0 && !left_limit_hit && !exhausted() {
+            left.clear(); //We have no choice but the rebuild the entire list because we cannot resume the search
+            remaining_left = count - right.len();
+            cid = first_chunk;
+
+            loop {
+                remaining_left -= Self::query_side(&snapshot, Self::query_left, cid, t, remaining_left, &mut left, budget);
+
+                if remaining_left == 0 || exhausted() {
+                    break;
+                }
+
+                if cid <= first_live {
+                    break;
+                }
+
+                cid -= 1;
+            }
+        }
+
+        for i in (0..left.len()).rev() {
+            callback(&left[i]);
+        }
+
+        for i in 0..right.len() {
+            callback(&right[i]);
+        }
+    }
+
+    pub fn query_previous<Func: FnMut(&TimeData<T, K>)>(&self, t: K, mut callback: Func) {
+        let snapshot = self.snapshot();
+        let first_chunk = Self::first_chunk(&snapshot, t);
+
+        let last_of_prev_chunk = Self::with_chunk(&snapshot, first_chunk, |rows| {
+            let i = rows.partition(t);
+            if i == 0 {
+                return true;
+            }
+
+            callback(&rows.get(i - 1, i)[0]);
+            false
+        });
+
+        if last_of_prev_chunk && first_chunk > 0 {
+            Self::with_chunk(&snapshot, first_chunk - 1, move |rows| {
+                let chunk_sz = rows.len();
+
+                if chunk_sz > 0 {
+                    callback(&rows.get(chunk_sz - 1, chunk_sz)[0]);
+                }
+            });
+        }
+    }
+
+    ///Same as `query_previous()`, except it walks backwards until it finds
+    ///an entry older than `t` that has any of `keys` (see `Indexed`). Stops
+    ///walking as soon as it reaches an entry older than `t_min`. Sealed
+    ///chunks and blocks that contain none of `keys` are skipped without
+    ///being read.
+    ///
+    ///Sealed chunks that are read, and the entry that is found, are charged
+    ///to `budget` (see `QueryIter::with_budget()`). If it is exceeded, the
+    ///walk stops and returns the cursor to resume it from, see
+    ///`query_previous_matching_from()`.
+    pub fn query_previous_matching<Func>(&self, t: K, t_min: K, keys: &[T::Key], budget: Option<&Budget>, callback: Func) -> Option<u64>
+        where Func: FnMut(&TimeData<T, K>)
+    {
+        let snapshot = self.snapshot();
+        let cid = Self::first_chunk(&snapshot, t);
+
+        Self::previous_matching(&snapshot, cid, IterStart::Time(t), t_min, keys, budget, callback)
+    }
+
+    ///Resumes a walk stopped by its budget, from the cursor returned by
+    ///`query_previous_matching()`. Only entries before the cursor are visited.
+    pub fn query_previous_matching_from<Func>(&self, cursor: u64, t_min: K, keys: &[T::Key], budget: Option<&Budget>, callback: Func) -> Option<u64>
+        where Func: FnMut(&TimeData<T, K>)
+    {
+        let start = IterStart::Index((cursor & 0xFFFF_FFFF) as usize);
+        Self::previous_matching(&self.snapshot(), (cursor >> 32) as usize, start, t_min, keys, budget, callback)
+    }
+
+    ///Walks backwards from chunk `cid`, whose entries before `end` are visited, see `query_previous_matching()`
+    fn previous_matching<Func>(snapshot: &Snapshot<T, K>, mut cid: usize, mut end: IterStart<K>, t_min: K, keys: &[T::Key], budget: Option<&Budget>, mut callback: Func) -> Option<u64>
+        where Func: FnMut(&TimeData<T, K>)
+    {
+        let first_live = snapshot.first_live;
+        let chunk_count = snapshot.old_chunks.len();
+
+        while cid <= chunk_count {
+            let mask = match Self::chunk_mask(snapshot, cid, keys) {
+                Some((0, min)) if min < t_min => return None, //None of the keys in there, the previous chunks are even older
+                Some((mask, _))               => mask,
+                None                          => !0
+            };
+
+            //None if the walk goes on with the previous chunk, Some(None) if it is over,
+            //and Some(Some(cursor)) if it has to be resumed from cursor
+            let stopped = if mask == 0 { None } else {
+                Self::with_chunk(snapshot, cid, |rows| {
+                    let mut i = match end {
+                        IterStart::Index(i) => usize::min(i, rows.len()),
+                        IterStart::Time(t)  => rows.partition(t)
+                    };
+
+                    let k_base = (cid as u64) << 32;
+                    if i > 0 && cid < chunk_count && !budget.map(|budget| budget.charge_chunk()).unwrap_or(true) {
+                        return Some(Some(k_base | (i as u64)));
+                    }
+
+                    while i > 0 {
+                        let block_start = (i - 1) / SUMMARY_BLOCK_SIZE * SUMMARY_BLOCK_SIZE;
+
+                        if mask & (1u64 << (block_start / SUMMARY_BLOCK_SIZE)) == 0 {
+                            i = block_start;
+                            continue;
+                        }
+
+                        //Entry i - 1 comes first
+                        for entry in rows.get(block_start, i).iter().rev() {
+                            if entry.time < t_min {
+                                return Some(None);
+                            }
+
+                            if entry.data.has_any_key(keys) {
+                                if !budget.map(|budget| budget.charge_entry()).unwrap_or(true) {
+                                    return Some(Some(k_base | (i as u64)));
+                                }
+
+                                callback(entry);
+                                return Some(None);
+                            }
+
+                            i -= 1;
+                        }
+                    }
+
+                    None
+                })
+            };
+
+            if let Some(cursor) = stopped {
+                return cursor;
+            }
+
+            if cid <= first_live {
+                break;
+            }
+
+            cid -= 1;
+            end = IterStart::Index(usize::MAX);
+        }
+
+        None
+    }
+
+    pub fn query<Func: FnMut(u64, &TimeData<T, K>)>(&self, min: K, max: Option<K>, mut callback: Func) {
+        let snapshot = self.snapshot();
+        let max = max.unwrap_or_else(|| self.get_max_time());
+        let old_chunks = &snapshot.old_chunks;
+
+        //Search into old_chunks
+        for i in Self::first_chunk(&snapshot, min)..old_chunks.len() {
+            let chunk = &old_chunks[i];
+
+            if chunk.min > max {
+                break;
+            }
+
+            let rows = chunk.data.rows();
+            let k_base = (i as u64) << 32;
+            let mut j = if chunk.min < min { rows.partition(min) } else { 0 };
+
+            //Block by block, so that chunks that store columns aren't rebuilt past `max`
+            while j < rows.len() {
+                let end = usize::min((j / SUMMARY_BLOCK_SIZE + 1) * SUMMARY_BLOCK_SIZE, rows.len());
+
+                for entry in rows.get(j, end).iter() {
+                    if chunk.max >= max && entry.data.should_stop_query(entry.time, max) {
+                        return;
+                    }
+
+                    callback(k_base | (j as u64), entry);
+                    j += 1;
+                }
+            }
+        }
+
+        //If we haven't left this function by now, that means we also need to check the current chunk
+        let chunk = snapshot.current_chunk.as_slice();
+        
+        if chunk.last().map(|c| min <= c.time).unwrap_or(false) {
+            let k_base = (old_chunks.len() as u64) << 32;
+            let start = if chunk[0].time < min { Self::binary_search(chunk, min) } else { 0 };
+
+            for i in start..chunk.len() {
+                let entry = &chunk[i];
+                if entry.data.should_stop_query(entry.time, max) {
+                    break;
+                }
+
+                callback(k_base | (i as u64), entry);
+            }
+        }
+    }
+
+    ///Splits [min; max] into buckets of `bucket_width` and calls `callback`
+    ///with each summary that fully fits into a bucket, and with every other entry
+    ///within [min; max], along with the index of the bucket.
+    ///
+    ///Chunks that fully fit into a bucket are summarized as a whole. Others are
+    ///only reloaded from disk if some of their block summaries straddle two
+    ///buckets, so wide time ranges can be aggregated cheaply. Summaries and
+    ///entries are visited in chronological order, block by block. Nothing is
+    ///visited if `bucket_width` is zero.
+    ///
+    ///Sealed chunks that have to be reloaded and entries passed to `callback`
+    ///are charged to `budget`, like `QueryIter::with_budget()` does. If it is
+    ///exceeded, the time from which buckets are incomplete is returned: nothing
+    ///at or after it was visited, so the query can be resumed from it. To that
+    ///end, entries that share a time are never split, and the first one is
+    ///always visited, even if that means going over budget.
+    pub fn query_buckets<Func>(&self, min: K, max: K, bucket_width: K, budget: Option<&Budget>, mut callback: Func) -> Option<K>
+        where Func: FnMut(usize, BucketItem<T, K>)
+    {
+        if bucket_width == K::default() {
+            return None;
+        }
+
+        let bucket_of = |t: K| (t.saturating_sub(min).as_f64() / bucket_width.as_f64()) as usize;
+        let snapshot = self.snapshot();
+        let chunk_count = snapshot.old_chunks.len();
+        let mut last = None; //Time of the last item visited
+
+        let charge_entry = |t: K, last: &mut Option<K>| {
+            let ok = budget.map(|budget| budget.charge_entry()).unwrap_or(true) || last.map(|last| last == t).unwrap_or(true);
+            if ok {
+                *last = Some(t);
+            }
+
+            ok
+        };
+
+        for i in Self::first_chunk(&snapshot, min)..chunk_count {
+            let chunk = &snapshot.old_chunks[i];
+
+            if chunk.min > max {
+                return None;
+            }
+
+            if chunk.min >= min && chunk.max <= max && bucket_of(chunk.min) == bucket_of(chunk.max) {
+                callback(bucket_of(chunk.min), BucketItem::Summary((i as u64) << 32, &chunk.summary));
+                last = Some(chunk.max);
+                continue;
+            }
+
+            let k_base = (i as u64) << 32;
+            let mut charged = false;
+            let mut rows = None; //Only read if needed
+
+            for (j, block) in chunk.blocks.iter().enumerate() {
+                if block.max < min {
+                    continue;
+                }
+
+                if block.min > max {
+                    break;
+                }
+
+                if block.min >= min && block.max <= max && bucket_of(block.min) == bucket_of(block.max) {
+                    callback(bucket_of(block.min), BucketItem::Summary(k_base | ((j * SUMMARY_BLOCK_SIZE) as u64), &block.summary));
+                    last = Some(block.max);
+                    continue;
+                }
+
+                //Blocks we can't use have to be read, the chunk is charged once for all of them
+                if !charged && !budget.map(|budget| budget.charge_chunk()).unwrap_or(true) && last != Some(block.min) {
+                    return Some(K::max(block.min, min));
+                }
+
+                charged = true;
+
+                let rows = rows.get_or_insert_with(|| chunk.data.rows());
+                let start = usize::min(j * SUMMARY_BLOCK_SIZE, rows.len()); //Broken chunk files have no entries
+                let end = usize::min(start + SUMMARY_BLOCK_SIZE, rows.len());
+
+                for (k, entry) in (start..end).zip(rows.get(start, end).iter()) {
+                    if entry.time >= min && entry.time <= max {
+                        if !charge_entry(entry.time, &mut last) {
+                            return Some(entry.time);
+                        }
+
+                        callback(bucket_of(entry.time), BucketItem::Entry(k_base | (k as u64), entry));
+                    }
+                }
+            }
+        }
+
+        //Current chunk isn't summarized
+        let chunk = snapshot.current_chunk.as_slice();
+
+        if chunk.last().map(|c| min <= c.time).unwrap_or(false) {
+            let k_base = (chunk_count as u64) << 32;
+            let start = if chunk[0].time < min { Self::binary_search(chunk, min) } else { 0 };
+
+            for k in start..chunk.len() {
+                let entry = &chunk[k];
+                if entry.time > max {
+                    break;
+                }
+
+                if !charge_entry(entry.time, &mut last) {
+                    return Some(entry.time);
+                }
+
+                callback(bucket_of(entry.time), BucketItem::Entry(k_base | (k as u64), entry));
+            }
+        }
+
+        None
+    }
+
+    ///Splits [min; max] into buckets of `bucket_width`, and folds every entry
+    ///of each bucket into an accumulator, which are then returned. Relies on
+    ///`query_buckets()`, hence `fold` has to handle summaries as well as entries,
+    ///and the accumulators are only complete before the time it returns, if any.
+    ///Returns no accumulator at all if `bucket_width` is zero.
+    ///
+    ///Careful, as one accumulator is allocated per bucket before anything is
+    ///read: callers are responsible for bounding their number.
+    pub fn aggregate<A, Fold>(&self, min: K, max: K, bucket_width: K, budget: Option<&Budget>, mut fold: Fold) -> (Vec<A>, Option<K>)
+        where A: Default, Fold: FnMut(&mut A, BucketItem<T, K>)
+    {
+        if bucket_width == K::default() {
+            return (Vec::new(), None);
+        }
+
+        let count = usize::max((max.saturating_sub(min).as_f64() / bucket_width.as_f64()).ceil() as usize, 1);
+        let mut ret: Vec<A> = (0..count).map(|_| A::default()).collect();
+
+        let next = self.query_buckets(min, max, bucket_width, budget, |bucket, item| fold(&mut ret[usize::min(bucket, count - 1)], item));
+        (ret, next)
+    }
+
+    ///Same as `query()`, except entries are returned through an iterator that copies
+    ///them chunk by chunk. No lock is held between two chunks, which means the
+    ///iteration can be paused without blocking the poller. Also see `iter_from()`.
+    pub fn iter(&self, min: K, max: Option<K>) -> QueryIter<T, K> {
+        let max = max.unwrap_or_else(|| self.get_max_time());
+        let snapshot = self.snapshot();
+
+        QueryIter {
+            cid: Self::first_chunk(&snapshot, min),
+            snapshot,
+            start: IterStart::Time(min),
+            max,
+            buffer: VecDeque::new(),
+            keys: None,
+            budget: None,
+            truncated: false,
+            done: false
+        }
+    }
+
+    ///Resumes an iteration from `cursor`, as returned by `QueryIter::next_cursor()`.
+    ///Cursors are just entry IDs, so this will also work with the IDs returned by
+    ///`query()`.
+    pub fn iter_from(&self, cursor: u64, max: Option<K>) -> QueryIter<T, K> {
+        QueryIter {
+            snapshot: self.snapshot(),
+            cid: (cursor >> 32) as usize,
+            start: IterStart::Index((cursor & 0xFFFF_FFFF) as usize),
+            max: max.unwrap_or_else(|| self.get_max_time()),
+            buffer: VecDeque::new(),
+            keys: None,
+            budget: None,
+            truncated: false,
+            done: false
+        }
+    }
+
+    pub fn get_entry_count(&self) -> usize {
+        let snapshot = self.snapshot();
+        snapshot.old_chunks.iter().map(|chunk| chunk.count).sum::<usize>() + snapshot.current_chunk.as_slice().len()
+    }
+
+    ///Same as `MemDB::for_each_key()`, except entries that are still pending
+    ///(see `MemDB::set_reorder_window()`) are ignored
+    pub fn for_each_key<F: FnMut(T::Key)>(&self, mut f: F) {
+        self.snapshot().for_each_key(&mut f);
+    }
+
+    ///Returns the chunks that could not be written to disk, which use RAM, and
+    ///those that could not be recovered or whose file broke since they were
+    ///mapped (see `MappedFile`), which are missing from query results.
+    pub fn get_health(&self) -> Vec<ChunkFailure<K>> {
+        let snapshot = self.snapshot();
+        let mut ret = self.contents.failed_chunks.lock().unwrap().clone();
+
+        for (cid, chunk) in snapshot.old_chunks.iter().enumerate().skip(snapshot.first_live) {
+            if let ChunkData::Mapped(file, _) = &chunk.data {
+                if let Some(error) = file.error() {
+                    ret.push(ChunkFailure {
+                        chunk: cid,
+                        min: chunk.min,
+                        max: chunk.max,
+                        count: chunk.count,
+                        error
+                    });
+                }
+            }
+        }
+
+        ret.sort_by_key(|failure| failure.chunk);
+        ret
+    }
+
+    ///Returns how many entries were dropped because they arrived after the reorder window
+    pub fn get_late_count(&self) -> u64 {
+        self.contents.late.load(Ordering::Relaxed)
+    }
+
+    pub fn get_max_time(&self) -> K {
+        K::from_bits(self.contents.max.load(Ordering::Acquire))
+    }
+
+    ///Returns the time of the oldest entry that wasn't dropped by the retention policy
+    pub fn get_min_time(&self) -> K {
+        self.contents.min_time()
+    }
+
+    ///Returns how many chunks are held in RAM (i.e. not memory-mapped), and the total number of chunks
+    pub fn get_stats(&self) -> (usize, usize) {
+        let snapshot = self.snapshot();
+        let in_ram = snapshot.old_chunks.iter().filter(|chunk| match chunk.data { ChunkData::Heap(_) => true, _ => false }).count() + 1;
+
+        (in_ram, snapshot.old_chunks.len() + 1)
+    }
+}
+
+impl<T: ShouldStopQuery<K> + FixedLayout + Summarize<K> + Indexed + Columnar, K: TimeKey> Accessor<T, K> {
+    ///Calls `callback` with the columns (see `Columnar`) of the entries within
+    ///[min; max], chunk by chunk, in chronological order. Sealed chunks are
+    ///read in place, so that only the pages of the columns are touched. The
+    ///current chunk (and chunks that couldn't be persisted) are converted on
+    ///the fly.
+    ///
+    ///Sealed chunks and the entries passed to `callback` are charged to
+    ///`budget`, like `QueryIter::with_budget()` does. If it is exceeded, the
+    ///time of the first entry that wasn't visited is returned, so that the scan
+    ///can be resumed from it. Just like `query_buckets()`, entries that share a
+    ///time are never split, and the first one is always visited.
+    pub fn scan_columns<Func: FnMut(&ColumnView<K>)>(&self, min: K, max: K, budget: Option<&Budget>, mut callback: Func) -> Option<K> {
+        let snapshot = self.snapshot();
+        let mut last = None; //Time of the last entry visited
+
+        for i in Self::first_chunk(&snapshot, min)..snapshot.old_chunks.len() {
+            let chunk = &snapshot.old_chunks[i];
+
+            if chunk.min > max {
+                return None;
+            }
+
+            if let ChunkData::Dropped = chunk.data {
+                continue;
+            }
+
+            if !budget.map(|budget| budget.charge_chunk()).unwrap_or(true) && last != Some(chunk.min) {
+                return Some(K::max(chunk.min, min));
+            }
+
+            let next = match chunk.data.columns() {
+                Some(view) if chunk.min >= min && chunk.max <= max => Self::charge_view(&view, budget, &mut last, &mut callback),
+                Some(view) => Self::charge_view(&view.restrict(min, max), budget, &mut last, &mut callback),
+                None       => Self::scan_rows(&chunk.data.entries(), min, max, budget, &mut last, &mut callback)
+            };
+
+            if next.is_some() {
+                return next;
+            }
+        }
+
+        Self::scan_rows(snapshot.current_chunk.as_slice(), min, max, budget, &mut last, &mut callback)
+    }
+
+    ///Calls `callback` with the part of `view` that `budget` allows, and
+    ///returns the time of the first entry left out, if any. `last` is the
+    ///time of the last entry visited by the scan.
+    fn charge_view<Func>(view: &ColumnView<K>, budget: Option<&Budget>, last: &mut Option<K>, callback: &mut Func) -> Option<K>
+        where Func: FnMut(&ColumnView<K>)
+    {
+        let times = view.times;
+        let mut count = budget.map(|budget| budget.charge_entries(times.len())).unwrap_or(times.len());
+
+        if count < times.len() {
+            //Entries that share a time aren't split, and the first time of the scan is always visited
+            let t = times[count];
+            count = times.binary_search_by(|&x| if x < t { std::cmp::Ordering::Less } else { std::cmp::Ordering::Greater }).unwrap_err();
+
+            if count == 0 && last.map(|last| last == t).unwrap_or(true) {
+                count = times.binary_search_by(|&x| if x <= t { std::cmp::Ordering::Less } else { std::cmp::Ordering::Greater }).unwrap_err();
+            }
+        }
+
+        if count == times.len() {
+            callback(view);
+        } else if count > 0 {
+            callback(&view.first(count));
+        }
+
+        if count > 0 {
+            *last = Some(times[count - 1]);
+        }
+
+        times.get(count).copied()
+    }
+
+    ///Builds the columns of the entries of `entries` within [min; max] and calls `callback`, see `charge_view()`
+    fn scan_rows<Func>(entries: &[TimeData<T, K>], min: K, max: K, budget: Option<&Budget>, last: &mut Option<K>, callback: &mut Func) -> Option<K>
+        where Func: FnMut(&ColumnView<K>)
+    {
+        let a = entries.binary_search_by(|e| if e.time < min { std::cmp::Ordering::Less } else { std::cmp::Ordering::Greater }).unwrap_err();
+        let b = entries.binary_search_by(|e| if e.time <= max { std::cmp::Ordering::Less } else { std::cmp::Ordering::Greater }).unwrap_err();
+
+        if a >= b {
+            return None;
+        }
+
+        let entries = &entries[a..b];
+        let times: Vec<K> = entries.iter().map(|e| e.time).collect();
+
+        //Stored as u64 so that columns are 8-byte aligned, just like in chunk files
+        let buffers: Vec<Vec<u64>> = (0..T::COLUMNS.len()).map(|i| {
+            let mut bytes = Vec::new();
+            T::write_column(entries, i, &mut bytes);
+
+            let mut buffer = vec![0u64; (bytes.len() + 7) / 8];
+            unsafe { std::ptr::copy_nonoverlapping(bytes.as_ptr(), buffer.as_mut_ptr() as *mut u8, bytes.len()); }
+
+            buffer
+        }).collect();
+
+        let columns = buffers.iter().zip(T::COLUMNS).map(|(buffer, &width)| {
+            unsafe { std::slice::from_raw_parts(buffer.as_ptr() as *const u8, width * entries.len()) }
+        }).collect();
+
+        Self::charge_view(&ColumnView { times: &times, columns }, budget, last, callback)
+    }
+}
+
+impl<'a, K: TimeKey> ColumnView<'a, K> {
+    ///Times of the entries, in chronological order
+    #[inline]
+    pub fn times(&self) -> &'a [K] {
+        self.times
+    }
+
+    ///Returns column `column` as a slice of `C`, which must be the type of the
+    ///field it stores. Panics if the size of `C` doesn't match the column's.
+    pub fn column<C: FixedLayout>(&self, column: usize) -> &'a [C] {
+        let bytes = self.columns[column];
+
+        assert_eq!(bytes.len(), self.times.len() * std::mem::size_of::<C>(), "column {} doesn't contain values of this type", column);
+        debug_assert_eq!(bytes.as_ptr() as usize % std::mem::align_of::<C>(), 0);
+
+        //Safe because C is plain data, and the size and alignment were checked
+        unsafe { std::slice::from_raw_parts(bytes.as_ptr() as *const C, self.times.len()) }
+    }
+
+    ///Only keeps the first `count` entries
+    fn first(&self, count: usize) -> ColumnView<'a, K> {
+        self.slice(0, count)
+    }
+
+    ///Only keeps entries [a; b)
+    fn slice(&self, a: usize, b: usize) -> ColumnView<'a, K> {
+        let count = self.times.len();
+
+        ColumnView {
+            times: &self.times[a..b],
+            columns: self.columns.iter().map(|bytes| {
+                let width = bytes.len() / count;
+                &bytes[a * width..b * width]
+            }).collect()
+        }
+    }
+
+    ///Only keeps the entries within [min; max]
+    fn restrict(&self, min: K, max: K) -> ColumnView<'a, K> {
+        let a = self.times.binary_search_by(|&t| if t < min { std::cmp::Ordering::Less } else { std::cmp::Ordering::Greater }).unwrap_err();
+        let b = self.times.binary_search_by(|&t| if t <= max { std::cmp::Ordering::Less } else { std::cmp::Ordering::Greater }).unwrap_err();
+
+        self.slice(a, usize::max(a, b))
+    }
+}
+
+impl Budget {
+    pub fn new(limits: BudgetLimits) -> Self {
+        Self {
+            limits,
+            start: Instant::now(),
+            entries: Cell::new(0),
+            chunks: Cell::new(0),
+            exceeded: Cell::new(None)
+        }
+    }
+
+    ///Returns which limit was exceeded, if any
+    pub fn exceeded(&self) -> Option<BudgetExceeded> {
+        self.exceeded.get()
+    }
+
+    fn exceed(&self, reason: BudgetExceeded) -> bool {
+        if self.exceeded.get().is_none() {
+            self.exceeded.set(Some(reason));
+        }
+
+        false
+    }
+
+    ///Returns true if one more entry can be returned
+    fn charge_entry(&self) -> bool {
+        self.charge_entries(1) == 1
+    }
+
+    ///Returns how many of `count` more entries can be returned
+    fn charge_entries(&self, count: usize) -> usize {
+        let entries = self.entries.get();
+
+        //Looking at the clock for every entry would be too slow, so it's only done every
+        //BUDGET_CLOCK_INTERVAL entries. Entries are always granted at first, to guarantee progress.
+        if entries > 0 && (entries + count) / BUDGET_CLOCK_INTERVAL > entries / BUDGET_CLOCK_INTERVAL && self.out_of_time() {
+            self.exceed(BudgetExceeded::WallTime);
+            return 0;
+        }
+
+        let granted = self.limits.max_entries.map(|max| usize::min(count, max.saturating_sub(entries))).unwrap_or(count);
+
+        if granted < count {
+            self.exceed(BudgetExceeded::Entries);
+        }
+
+        self.entries.set(entries + granted);
+        granted
+    }
+
+    ///Returns true if one more sealed chunk can be read
+    fn charge_chunk(&self) -> bool {
+        let chunks = self.chunks.get();
+
+        if chunks > 0 {
+            if self.exceeded.get().is_some() {
+                return false;
+            }
+
+            if self.limits.max_chunks.map(|max| chunks >= max).unwrap_or(false) {
+                return self.exceed(BudgetExceeded::Chunks);
+            }
+
+            if self.out_of_time() {
+                return self.exceed(BudgetExceeded::WallTime);
+            }
+        }
+
+        self.chunks.set(chunks + 1);
+        true
+    }
+
+    fn out_of_time(&self) -> bool {
+        self.limits.max_time.map(|max| self.start.elapsed() >= max).unwrap_or(false)
+    }
+}
+
+impl<'a, T: ShouldStopQuery<K> + FixedLayout + Summarize<K> + Indexed + Columnar, K: TimeKey> QueryIter<'a, T, K> {
+    ///Returns the index of the first entry of `rows` the iteration starts from
+    fn first_index(rows: &Rows<T, K>, start: IterStart<K>) -> usize {
+        match start {
+            IterStart::Index(i)  => i,
+            IterStart::Time(min) => rows.partition(min)
+        }
+    }
+
+    fn refill(&mut self) {
+        while self.buffer.is_empty() && !self.done && !self.truncated {
+            let chunk_count = self.snapshot.old_chunks.len();
+
+            if self.cid > chunk_count {
+                self.done = true;
+                break;
+            }
+
+            let (cid, start, max) = (self.cid, self.start, self.max);
+            let mask = match self.keys.as_ref().and_then(|keys| Accessor::chunk_mask(&self.snapshot, cid, keys)) {
+                Some((0, min)) => {
+                    //Nothing to see in this chunk, no need to touch its entries
+                    self.done = min > max;
+                    self.cid += 1;
+                    self.start = IterStart::Index(0);
+                    continue;
+                },
+                Some((mask, _)) => mask,
+                None            => !0
+            };
+
+            if cid < chunk_count && !self.budget.map(|budget| budget.charge_chunk()).unwrap_or(true) {
+                self.truncated = true;
+                break;
+            }
+
+            let keys = self.keys.as_ref();
+            let buffer = &mut self.buffer;
+
+            let stopped = Accessor::with_chunk(&self.snapshot, cid, |rows| {
+                let k_base = (cid as u64) << 32;
+                let mut i = Self::first_index(rows, start);
+
+                while i < rows.len() {
+                    let end = usize::min((i / SUMMARY_BLOCK_SIZE + 1) * SUMMARY_BLOCK_SIZE, rows.len());
+
+                    if mask & (1u64 << (i / SUMMARY_BLOCK_SIZE)) == 0 {
+                        i = end;
+                        continue;
+                    }
+
+                    for entry in rows.get(i, end).iter() {
+                        if entry.data.should_stop_query(entry.time, max) {
+                            return true;
+                        }
+
+                        if keys.map(|keys| entry.data.has_any_key(keys)).unwrap_or(true) {
+                            buffer.push_back((k_base | (i as u64), *entry));
+                        }
+
+                        i += 1;
+                    }
+                }
+
+                false
+            });
+
+            if stopped || cid >= chunk_count {
+                //Either we're past `max` or that was the current chunk
+                self.done = true;
+            }
+
+            self.cid += 1;
+            self.start = IterStart::Index(0);
+        }
+    }
+
+    ///Only yields the entries that have any of `keys` (see `Indexed`). Sealed
+    ///chunks and blocks that contain none of them are skipped. Cursors remain
+    ///valid, as long as the same keys are specified when resuming.
+    pub fn with_keys(mut self, keys: Vec<T::Key>) -> Self {
+        self.keys = Some(keys);
+        self
+    }
 
-use std::time::Instant;
-use std::sync::{RwLock, Arc, Mutex, RwLockReadGuard};
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::mem::MaybeUninit;
-use std::path::PathBuf;
-use std::io::Error as IOResult;
-use std::fs;
+    ///Makes the iteration stop once `budget` is exceeded. `truncated()` then
+    ///returns true and `next_cursor()` tells where to resume from.
+    pub fn with_budget(mut self, budget: &'a Budget) -> Self {
+        self.budget = Some(budget);
+        self
+    }
 
-use bincode::Error as BincodeError;
-use serde::{Serialize, Deserialize, de::DeserializeOwned};
-use log::{error, warn, debug};
+    ///Returns true if the iteration was stopped because its budget was exceeded
+    pub fn truncated(&self) -> bool {
+        self.truncated
+    }
 
-#[derive(Serialize, Deserialize, Copy, Clone)]
-pub struct TimeData<T> {
-    pub time: f64,
-    pub data: T
+    ///Returns the cursor pointing to the next entry, or `None` if the
+    ///iteration is over. Pass it to `Accessor::iter_from()` to resume.
+    pub fn next_cursor(&mut self) -> Option<u64> {
+        self.refill();
+
+        match self.buffer.front() {
+            Some(&(k, _)) => Some(k),
+            None if self.truncated => {
+                //Stopped before reading chunk `cid`
+                let (cid, start) = (self.cid, self.start);
+                let first = Accessor::with_chunk(&self.snapshot, cid, |rows| Self::first_index(rows, start));
+
+                Some(((cid as u64) << 32) | (first as u64))
+            },
+            None => None
+        }
+    }
 }
 
-struct Chunk<T> {
-    data: Option<Vec<TimeData<T>>>, //None if unloaded
-    min: f64,
-    max: f64,
-    last_access: AtomicU64
-}
+impl<'a, T: ShouldStopQuery<K> + FixedLayout + Summarize<K> + Indexed + Columnar, K: TimeKey> Iterator for QueryIter<'a, T, K> {
+    type Item = (u64, TimeData<T, K>);
 
-struct Shared<T>
-{
-    old_chunks: Vec<Chunk<T>>,
-    current_chunk: Vec<TimeData<T>>,
-    max: f64
+    fn next(&mut self) -> Option<Self::Item> {
+        self.refill();
+
+        if self.buffer.is_empty() || self.truncated {
+            return None;
+        }
+
+        if !self.budget.map(|budget| budget.charge_entry()).unwrap_or(true) {
+            self.truncated = true;
+            return None;
+        }
+
+        self.buffer.pop_front()
+    }
 }
 
-struct Contents<T>
-{
-    shared: RwLock<Shared<T>>,
-    loaded_chunks: Mutex<Vec<usize>>,
-    save_path: PathBuf,
-    name: String
+impl<T: Summarize<K> + Indexed, K: TimeKey> Clone for Accessor<T, K> {
+    fn clone(&self) -> Self {
+        Self {
+            contents: self.contents.clone(),
+            last_range: Mutex::new(None) //Clones track their own queries
+        }
+    }
 }
 
-pub struct Accessor<T>
-{
-    contents: Arc<Contents<T>>
+impl SystemClock {
+    pub fn new() -> Self {
+        Self(Instant::now())
+    }
 }
 
-pub struct MemDB<T> {
-    contents: Arc<Contents<T>>,
-    unload_list: Vec<usize>
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        self.0.elapsed()
+    }
 }
 
-const SWAP_THRESHOLD: usize = 32768;
-const UNLOAD_THRESHOLD: u64 = 60; //In seconds
-static mut START_INSTANT: MaybeUninit<Instant> = MaybeUninit::uninit();
+impl ManualClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
 
-#[derive(Debug)]
-enum ChunkSaveError
-{
-    FileCreateError(IOResult),
-    SerializeError(BincodeError),
-    FileSyncError(IOResult)
+    pub fn advance(&self, by: Duration) {
+        self.0.fetch_add(by.as_nanos() as u64, Ordering::Relaxed);
+    }
 }
 
-#[derive(Debug)]
-enum ChunkLoadError
-{
-    FileOpenError(IOResult),
-    DeserializeError(BincodeError)
+impl Clock for ManualClock {
+    fn now(&self) -> Duration {
+        Duration::from_nanos(self.0.load(Ordering::Relaxed))
+    }
 }
 
-impl<T: Serialize + DeserializeOwned> Chunk<T> {
-    fn try_saving_to(&mut self, path: PathBuf) -> Result<(), ChunkSaveError> {
-        if path.exists() {
-            //Already saved! We're good!
-            self.data = None;
-            return Ok(());
-        }
-
-        let data = self.data.as_ref().unwrap();
-        let file = fs::File::create(path.as_path()).map_err(ChunkSaveError::FileCreateError)?;
-        bincode::serialize_into(&file, &data).map_err(ChunkSaveError::SerializeError)?;
+impl MemDbContext {
+    ///Creates a context using the real time, see `SystemClock`
+    pub fn new() -> Self {
+        Self::with_clock(Arc::new(SystemClock::new()))
+    }
 
-        if let Err(err) = file.sync_all() {
-            drop(file); //Make sure its closed otherwise we won't be able to delete it
-            
-            if let Err(remove_err) = fs::remove_file(path) {
-                warn!("Failed to sync chunk contents to disk, and then failed to remove it!! Things will go wrong... error: {}", remove_err);
-            }
-            
-            Err(ChunkSaveError::FileSyncError(err))
-        } else {
-            self.data = None;
-            Ok(())
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            clock
         }
     }
 
-    fn try_loading_from(&mut self, path: PathBuf) -> Result<(), ChunkLoadError> {
-        let file = fs::File::open(path.as_path()).map_err(ChunkLoadError::FileOpenError)?;
-        let data = bincode::deserialize_from(&file).map_err(ChunkLoadError::DeserializeError)?;
-        drop(file);
+    #[inline]
+    fn now(&self) -> Duration {
+        self.clock.now()
+    }
+}
 
-        self.data = Some(data);
-        Ok(())
+impl Default for MemDbContext {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
-///MemDB is just a fancy name for "huge vec". It can store a lot
-///of data indexed by a "time" field. It can then perform queries
-///based on this field.
-///
-///Data is inserted but never deleted or modified. Internally,
-///data is split into chunks, each one containing a maximum of
-///`SWAP_THRESHOLD` entries. Each chunk keeps track of the last
-///time they were queried. To save memory, they can decide to
-///save themselves to disk (inside `save_path`) and unload
-///themselves from the RAM, until they are queried again.
-///
-///Another restriction (in addition to the "insert-only"
-///constraint) is that the `time` field inside the `TimeData`
-///struct can only be increasing. This enables fast queries
-///through the help of binary search algorithms.
-impl<T: Serialize + DeserializeOwned> MemDB<T> {
-    ///Creates a MemDB instance
-    ///
-    ///Unsafe because it is the user's job to make sure he calls
-    ///`memdb::init()` before creating any MemDB instance
-    ///
-    ///Also, `save_path` must point to an empty directory and
-    ///this instance of MemDB should be the only entity able to
-    ///write to this folder. It is the user's responsibility to
-    ///erase all files contained in this folder before calling
-    ///this function.
-    pub unsafe fn new(name: String, save_path: PathBuf) -> Self {
-        Self {
-            contents: Arc::new(Contents {
-                shared: RwLock::new(Shared {
-                    old_chunks: Vec::new(),
-                    current_chunk: Vec::new(),
-                    max: 0.0
-                }),
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::{LitePlotData, LiteZoneData, PlotBucket, PlotSummary};
+
+    use std::sync::atomic::AtomicBool;
+    use std::thread;
+
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+
+    const READERS: usize = 4;
+    const ENTRIES: usize = SWAP_THRESHOLD * 8 + 123;
+    const BATCH_SIZE: usize = 1000;
+    const SEEDS: u64 = 16;
+    const ROUNDS: usize = 8; //Per seed, each ending with a reload
+    const QUERIES: usize = 64; //Per round and per kind of query
+    const NAMES: usize = 4;
+    const SEAL_IDLE: Duration = Duration::from_secs(1);
+
+    ///Save directory and clock of a test. The directory starts empty, and is
+    ///removed when the fixture is dropped, even if the test fails.
+    struct Fixture {
+        dir: PathBuf,
+        clock: Arc<ManualClock>,
+        context: MemDbContext
+    }
 
-                loaded_chunks: Mutex::new(Vec::new()),
-                save_path,
-                name
-            }),
+    impl Fixture {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("temporal-lens-memdb-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
 
-            unload_list: Vec::new()
-        }
-    }
+            let clock = Arc::new(ManualClock::new());
+            let context = MemDbContext::with_clock(clock.clone());
 
-    pub fn push(&self, entry: TimeData<T>) {
-        let mut contents = self.contents.shared.write().unwrap();
-        
-        if entry.time < contents.max {
-            error!("Dropping entry that is older than the last entry inserted!");
-            return;
+            Self { dir, clock, context }
         }
 
-        contents.max = entry.time;
-        contents.current_chunk.push(entry);
+        ///Creates a MemDB in the directory, sealed after `SEAL_IDLE`
+        fn db<T: FixedLayout + Summarize + Indexed + Columnar>(&self) -> MemDB<T> {
+            let mut ret = MemDB::new(&self.context, "test_db".to_string(), self.dir.clone());
+            ret.set_seal_idle(Some(SEAL_IDLE));
+            ret
+        }
 
-        let current_chunk_size = contents.current_chunk.len();
+        ///Recovers the MemDB of the directory, sealed after `SEAL_IDLE`
+        fn recover<T: FixedLayout + Summarize + Indexed + Columnar>(&self) -> MemDB<T> {
+            let mut ret = MemDB::recover(&self.context, "test_db".to_string(), self.dir.clone()).unwrap();
+            ret.set_seal_idle(Some(SEAL_IDLE));
+            ret
+        }
 
-        if current_chunk_size % 1000 == 0 {
-            debug!("Current chunk of {} contains {} elements", self.contents.name, current_chunk_size);
+        ///Lets `db` idle until its current chunk gets sealed
+        fn seal<T: FixedLayout + Summarize + Indexed + Columnar>(&self, db: &mut MemDB<T>) -> bool {
+            self.clock.advance(SEAL_IDLE);
+            db.seal_if_idle()
         }
 
-        if current_chunk_size >= SWAP_THRESHOLD {
-            //Exchange with a new vec
-            let mut vec = Vec::new();
-            std::mem::swap(&mut contents.current_chunk, &mut vec);
+        fn path(&self, file: &str) -> PathBuf {
+            self.dir.join(file)
+        }
+    }
 
-            //Store it into the chunk list
-            let chunk = Chunk {
-                min: vec.first().unwrap().time,
-                max: vec.last().unwrap().time,
-                data: Some(vec),
-                last_access: AtomicU64::new(unsafe { START_INSTANT.get_ref().elapsed().as_secs() }) //The 'unsafe' part was the call to new()
-            };
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
 
-            let index = contents.old_chunks.len();
-            contents.old_chunks.push(chunk);
-            drop(contents);
+    ///Times of all the entries of `db`
+    fn times<T: FixedLayout + Summarize + Indexed + Columnar>(db: &MemDB<T>) -> Vec<u64> {
+        let mut ret = Vec::new();
+        db.new_accessor().query(0, None, |_, e| ret.push(e.time));
+        ret
+    }
 
-            //Mark it as loaded
-            self.contents.loaded_chunks.lock().unwrap().push(index);
+    fn entry(time: u64, value: usize, name: usize) -> TimeData<LitePlotData> {
+        TimeData {
+            time,
+            data: LitePlotData::new(0, value as f64, name)
         }
     }
 
-    pub fn unload_old_chunks(&mut self) {
-        let now = unsafe { START_INSTANT.get_ref().elapsed().as_secs() };
-        let contents = self.contents.shared.read().unwrap();
-        let mut loaded_chunks = self.contents.loaded_chunks.lock().unwrap();
-
-        for i in (0..loaded_chunks.len()).rev() {
-            let index = loaded_chunks[i];
+    fn values(entries: &[TimeData<LitePlotData>]) -> Vec<(u64, f64)> {
+        entries.iter().map(|e| (e.time, e.data.value)).collect()
+    }
 
-            if now - contents.old_chunks[index].last_access.load(Ordering::Relaxed) >= UNLOAD_THRESHOLD {
-                self.unload_list.push(index);
-                loaded_chunks.remove(i);
-            }
-        }
+    ///Index of the first entry of `reference` that isn't older than `t`
+    fn partition(reference: &[TimeData<LitePlotData>], t: u64) -> usize {
+        reference.iter().position(|e| e.time >= t).unwrap_or(reference.len())
+    }
 
-        drop(contents);
+    ///What `Accessor::query_count()` should return, see `partition()`
+    fn naive_query_count(reference: &[TimeData<LitePlotData>], t: u64, count: usize) -> &[TimeData<LitePlotData>] {
+        let p = partition(reference, t);
+        let mut left = usize::min(count / 2, p);
+        let right = usize::min(count - left, reference.len() - p);
 
-        if self.unload_list.len() > 0 {
-            let mut contents = self.contents.shared.write().unwrap();
+        if left + right < count {
+            left = usize::min(count - right, p);
+        }
 
-            for &i in &self.unload_list {
-                let chunk = &mut contents.old_chunks[i];
-                let mut path = self.contents.save_path.clone();
-                path.push(i.to_string());
+        &reference[p - left..p + right]
+    }
 
-                if let Err(err) = chunk.try_saving_to(path) {
-                    //Failed to save the chunk! Oh noes!
-                    error!("Could not save chunk: {:?}", err);
-                    loaded_chunks.push(i);
+    ///Picks a time around the entries of `reference`: one of their times, a time
+    ///right before or after one of them, or a time outside of the whole range.
+    fn random_time(rng: &mut StdRng, reference: &[TimeData<LitePlotData>]) -> u64 {
+        let last = reference.last().map(|e| e.time).unwrap_or(0);
+
+        match (rng.gen_range(0, 8), reference.is_empty()) {
+            (0, _)     => 0,
+            (1, _)     => last + rng.gen_range(1, 10),
+            (_, true)  => rng.gen_range(0, 10),
+            (k, false) => {
+                let t = reference[rng.gen_range(0, reference.len())].time;
+
+                match k {
+                    2 => t.saturating_sub(1),
+                    3 => t + 1,
+                    _ => t
                 }
             }
-
-            debug!("Saved {} chunks onto disk", self.unload_list.len());
-            self.unload_list.clear();
         }
     }
 
-    pub fn new_accessor(&self) -> Accessor<T> {
-        Accessor {
-            contents: self.contents.clone()
+    ///Folds the items of `query_buckets()` into per-series buckets
+    fn fold_plot_item(acc: &mut PlotSummary, item: BucketItem<LitePlotData>) {
+        match item {
+            BucketItem::Summary(_, summary) => for (&name, bucket) in summary {
+                acc.entry(name).and_modify(|dst| dst.merge(bucket)).or_insert(*bucket);
+            },
+
+            BucketItem::Entry(_, e) => {
+                acc.entry(e.data.name)
+                    .and_modify(|dst| dst.add(e.time, e.data.value))
+                    .or_insert_with(|| PlotBucket::new(e.time, e.data.value));
+            }
         }
     }
-}
 
-impl<T: Serialize + DeserializeOwned + ShouldStopQuery> Accessor<T> {
-    fn prepare_query(&self, min: f64, max: Option<f64>, lookup_list: &mut Vec<usize>) -> (f64, f64, RwLockReadGuard<Shared<T>>) {
-        //Load all unloaded chunks that are potentially needed
-        let shared = self.contents.shared.read().unwrap();
-        let now = unsafe { START_INSTANT.get_ref().elapsed().as_secs() };
-        let mut should_load = false;
+    ///What `Accessor::aggregate()` should return with `fold_plot_item()`, when it isn't truncated
+    fn naive_aggregate(reference: &[TimeData<LitePlotData>], min: u64, max: u64, width: u64) -> Vec<PlotSummary> {
+        let count = usize::max(((max - min) as f64 / width as f64).ceil() as usize, 1);
+        let mut ret: Vec<PlotSummary> = (0..count).map(|_| PlotSummary::default()).collect();
 
-        //Compute absolute min and max if needed
-        let min = if min < 0.0 { min + shared.max } else { min };
-        let max = max.unwrap_or(shared.max);
-
-        //Determine first chunk
-        let first_chunk;
-        if shared.old_chunks.len() == 0 || min <= shared.old_chunks[0].max {
-            first_chunk = 0;
-        } else if min > shared.old_chunks.last().unwrap().max {
-            first_chunk = shared.old_chunks.len();
-        } else {
-            first_chunk = Self::binary_search_chunk(&shared.old_chunks, min);
+        for e in reference.iter().filter(|e| e.time >= min && e.time <= max) {
+            let bucket = usize::min(((e.time - min) / width) as usize, count - 1);
+            fold_plot_item(&mut ret[bucket], BucketItem::Entry(0, e));
         }
 
-        //Search into old_chunks, caller will take care of the current chunk
-        for i in first_chunk..shared.old_chunks.len() {
-            let chunk = &shared.old_chunks[i];
+        ret
+    }
 
-            if chunk.min > max {
-                break;
-            }
+    ///Comparable contents of a bucket, sorted by series
+    fn bucket_values(summary: &PlotSummary) -> Vec<(usize, u64, f64, f64, f64, f64, u64, u64)> {
+        let mut ret: Vec<_> = summary.iter().map(|(&name, b)| (name, b.count, b.min, b.max, b.sum, b.last, b.first_time, b.last_time)).collect();
+        ret.sort_by_key(|v| v.0);
+        ret
+    }
+
+    ///Iterates over [min; max] page by page, like the endpoints do: each page stops
+    ///after `limit` entries or once its budget is exceeded, and the next one resumes
+    ///from its cursor. Returns the (ID, value) of the entries, and the cursors.
+    fn paginate(accessor: &Accessor<LitePlotData>, min: u64, max: u64, keys: &Option<Vec<usize>>, limit: usize, limits: BudgetLimits) -> (Vec<(u64, f64)>, Vec<u64>) {
+        let mut entries = Vec::new();
+        let mut cursors = Vec::new();
+        let mut cursor = None;
 
-            if chunk.data.is_none() {
-                should_load = true;
+        loop {
+            let budget = Budget::new(limits);
+            let mut iter = match cursor {
+                Some(cursor) => accessor.iter_from(cursor, Some(max)),
+                None         => accessor.iter(min, Some(max))
+            }.with_budget(&budget);
+
+            if let Some(keys) = keys {
+                iter = iter.with_keys(keys.clone());
             }
 
-            lookup_list.push(i);
-            chunk.last_access.store(now, Ordering::Relaxed);
+            entries.extend(iter.by_ref().take(limit).map(|(k, e)| (k, e.data.value)));
+
+            match iter.next_cursor() {
+                Some(next) => {
+                    assert!(cursor.map(|c| next > c).unwrap_or(true), "iteration makes no progress from cursor {:x}", next);
+                    cursors.push(next);
+                    cursor = Some(next);
+                },
+
+                None => return (entries, cursors)
+            }
         }
+    }
 
-        if should_load {
-            drop(shared);
-            let mut shared = self.contents.shared.write().unwrap();
-            let mut num_reloaded = 0;
+    ///(ID, value) of the entries of `all` from `cursor` on that are in [min; max] and have one of `keys`
+    fn naive_iter_from(all: &[(u64, TimeData<LitePlotData>)], cursor: u64, min: u64, max: u64, keys: &Option<Vec<usize>>) -> Vec<(u64, f64)> {
+        all.iter()
+            .filter(|(k, e)| *k >= cursor && e.time >= min && e.time <= max && keys.as_ref().map(|keys| keys.contains(&e.data.name)).unwrap_or(true))
+            .map(|(k, e)| (*k, e.data.value))
+            .collect()
+    }
 
-            for &i in &*lookup_list {
-                let chunk = &mut shared.old_chunks[i];
+    ///Compares `query()`, `query_count()`, `query_previous()` and friends with
+    ///naive implementations running on a plain vec, with and without budgets. Chunks are kept small (and
+    ///sometimes sealed early or dropped) so that most queries straddle chunk
+    ///boundaries, and the MemDB is regularly reloaded from disk.
+    #[test]
+    fn queries_match_naive_reference() {
+        for seed in 0..SEEDS {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let fixture = Fixture::new(&format!("queries-{}", seed));
+            let seal_span = rng.gen_range(1, 40);
+            let mut db: MemDB<LitePlotData> = fixture.db();
+            let mut reference = Vec::new();
+            let mut time = rng.gen_range(0, 5);
+
+            for round in 0..ROUNDS {
+                db.set_seal_span(Some(seal_span));
+
+                //Times often repeat, including across chunks
+                for _ in 0..rng.gen_range(0, 20) {
+                    let start = reference.len();
+
+                    for _ in 0..rng.gen_range(1, 20) {
+                        time += rng.gen_range(0, 4);
+                        //Some names are rare, so that whole chunks don't contain them
+                        let name = if rng.gen_range(0, 16) == 0 { rng.gen_range(0, NAMES) } else { 0 };
+                        reference.push(entry(time, reference.len(), name));
+                    }
 
-                if chunk.data.is_none() {
-                    let mut path = self.contents.save_path.clone();
-                    path.push(i.to_string());
+                    db.extend(&reference[start..]);
 
-                    if let Err(err) = chunk.try_loading_from(path) {
-                        error!("Could not reload chunk: {:?}", err);
-                    } else {
-                        self.contents.loaded_chunks.lock().unwrap().push(i);
-                        num_reloaded += 1;
+                    if rng.gen_range(0, 8) == 0 {
+                        fixture.seal(&mut db);
                     }
                 }
-            }
-
-            drop(shared);
-            debug!("Reloaded {} chunks from disk", num_reloaded);
-            (min, max, self.contents.shared.read().unwrap()) //TODO: Switch to parking_lot and use downgrade
-        } else {
-            (min, max, shared)
-        }
-    }
 
-    ///Returns i such that data[i].time >= min and data[i - 1].time < min
-    ///Careful, as it assumes that min > data[0].time
-    fn binary_search(data: &[TimeData<T>], min: f64) -> usize {
-        let mut a = 0;
-        let mut b = data.len();
+                db.flush();
 
-        loop {
-            let half = (a + b) >> 1;
+                if rng.gen_range(0, 4) == 0 {
+                    let cutoff = random_time(&mut rng, &reference);
+                    db.drop_chunks_before(cutoff);
+                }
 
-            if data[half].time >= min {
-                if data[half - 1].time < min {
-                    return half;
+                let accessor = db.new_accessor();
+
+                //Dropped chunks are always the oldest ones
+                let mut all = Vec::new();
+                let mut ids = Vec::new();
+                accessor.query(0, None, |k, e| {
+                    ids.push(k);
+                    all.push(*e);
+                });
+
+                assert!(values(&reference).ends_with(&values(&all)), "seed {}, round {}: query(0, None) is not a suffix of what was pushed", seed, round);
+                assert!(ids.windows(2).all(|w| w[0] < w[1]), "seed {}, round {}: entry IDs aren't increasing", seed, round);
+                reference = all;
+
+                for _ in 0..QUERIES {
+                    let (a, b) = (random_time(&mut rng, &reference), random_time(&mut rng, &reference));
+                    let (min, max) = (u64::min(a, b), u64::max(a, b));
+                    let max = if rng.gen_range(0, 8) == 0 { None } else { Some(max) };
+                    let expected: Vec<_> = reference.iter().filter(|e| e.time >= min && max.map(|max| e.time <= max).unwrap_or(true)).collect();
+                    let mut result = Vec::new();
+
+                    accessor.query(min, max, |k, e| {
+                        let i = ids.binary_search(&k).unwrap_or_else(|_| panic!("seed {}, round {}: unknown entry ID {:x}", seed, round, k));
+                        assert_eq!(reference[i].data.value, e.data.value, "seed {}, round {}: entry ID {:x} refers to another entry", seed, round, k);
+                        result.push(*e);
+                    });
+
+                    assert_eq!(values(&result), values(&expected.into_iter().cloned().collect::<Vec<_>>()), "seed {}, round {}: query({}, {:?})", seed, round, min, max);
                 }
 
-                b = half;
-            } else {
-                a = half;
-            }
-        }
-    }
+                for _ in 0..QUERIES {
+                    let t = random_time(&mut rng, &reference);
+                    let count = rng.gen_range(0, 64);
+                    let mut result = Vec::new();
 
-    ///Returns i such that data[i].max >= min and data[i - 1].max < min
-    ///Careful, as it assumes that min > data[0].max
-    fn binary_search_chunk(data: &[Chunk<T>], min: f64) -> usize {
-        let mut a = 0;
-        let mut b = data.len();
+                    accessor.query_count(t, count, None, |e| result.push(*e));
+                    assert_eq!(values(&result), values(naive_query_count(&reference, t, count)), "seed {}, round {}: query_count({}, {})", seed, round, t, count);
+                }
 
-        loop {
-            let half = (a + b) >> 1;
+                for _ in 0..QUERIES {
+                    let t = random_time(&mut rng, &reference);
+                    let p = partition(&reference, t);
+                    let expected = if p > 0 { vec![reference[p - 1]] } else { Vec::new() };
+                    let mut result = Vec::new();
 
-            if data[half].max >= min {
-                if data[half - 1].max < min {
-                    return half;
+                    accessor.query_previous(t, |e| result.push(*e));
+                    assert_eq!(values(&result), values(&expected), "seed {}, round {}: query_previous({})", seed, round, t);
                 }
 
-                b = half;
-            } else {
-                a = half;
-            }
-        }
-    }
-
-    fn with_chunk<U, Func: FnOnce(&Vec<TimeData<T>>) -> U>(&self, cid: usize, func: Func) -> Option<U> {
-        let now = unsafe { START_INSTANT.get_ref().elapsed().as_secs() };
-        let shared = self.contents.shared.read().unwrap();
+                for _ in 0..QUERIES {
+                    let (a, b) = (random_time(&mut rng, &reference), random_time(&mut rng, &reference));
+                    let (t_min, t) = (u64::min(a, b), u64::max(a, b));
+                    let name = rng.gen_range(0, NAMES);
+                    let p = partition(&reference, t);
+                    let expected: Vec<_> = reference[..p].iter().rev().take_while(|e| e.time >= t_min).find(|e| e.data.name == name).cloned().into_iter().collect();
+                    let mut result = Vec::new();
 
-        if cid >= shared.old_chunks.len() {
-            Some(func(&shared.current_chunk))
-        } else if let Some(data) = shared.old_chunks[cid].data.as_ref() {
-            shared.old_chunks[cid].last_access.store(now, Ordering::Relaxed);
-            Some(func(data))
-        } else {
-            drop(shared);
+                    accessor.query_previous_matching(t, t_min, &[name], None, |e| result.push(*e));
+                    assert_eq!(values(&result), values(&expected), "seed {}, round {}: query_previous_matching({}, {}, {})", seed, round, t, t_min, name);
 
-            let mut shared = self.contents.shared.write().unwrap();
-            let chunk = &mut shared.old_chunks[cid];
+                    //One sealed chunk per call at most: the walk has to be resumed until it's over
+                    let limits = BudgetLimits { max_chunks: Some(1), ..Default::default() };
+                    result.clear();
 
-            if chunk.data.is_none() {
-                let mut path = self.contents.save_path.clone();
-                path.push(cid.to_string());
+                    let mut cursor = accessor.query_previous_matching(t, t_min, &[name], Some(&Budget::new(limits)), |e| result.push(*e));
+                    while let Some(c) = cursor {
+                        cursor = accessor.query_previous_matching_from(c, t_min, &[name], Some(&Budget::new(limits)), |e| result.push(*e));
+                    }
 
-                if let Err(err) = chunk.try_loading_from(path) {
-                    error!("Could not reload chunk: {:?}", err);
-                    return None;
+                    assert_eq!(values(&result), values(&expected), "seed {}, round {}: resumed query_previous_matching({}, {}, {})", seed, round, t, t_min, name);
                 }
 
-                self.contents.loaded_chunks.lock().unwrap().push(cid);
-            }
+                for _ in 0..QUERIES {
+                    let (a, b) = (random_time(&mut rng, &reference), random_time(&mut rng, &reference));
+                    let (mut min, max) = (u64::min(a, b), u64::max(a, b));
+                    let expected: Vec<u64> = reference.iter().map(|e| e.time).filter(|&t| t >= min && t <= max).collect();
+                    let limits = BudgetLimits { max_entries: Some(rng.gen_range(1, 40)), ..Default::default() };
+                    let mut result = Vec::new();
+
+                    //Resumed from the time it returns until it's over
+                    while let Some(next) = accessor.scan_columns(min, max, Some(&Budget::new(limits)), |view| result.extend_from_slice(view.times())) {
+                        assert!(next > min, "seed {}, round {}: scan_columns({}, {}) makes no progress", seed, round, min, max);
+                        min = next;
+                    }
 
-            chunk.last_access.store(now, Ordering::Relaxed);
-            drop(chunk);
-            drop(shared);
+                    assert_eq!(result, expected, "seed {}, round {}: resumed scan_columns({}, {})", seed, round, min, max);
+                }
 
-            let shared = self.contents.shared.read().unwrap();
-            Some(func(shared.old_chunks[cid].data.as_ref().unwrap()))
-        }
-    }
+                //Unsealed entries are lost when reloading
+                fixture.seal(&mut db);
+                drop(accessor);
+                drop(db);
 
-    fn query_left(&self, cid: usize, t: f64, max: usize, dst: &mut Vec<TimeData<T>>) -> usize where T: Copy {
-        self.with_chunk(cid, move |chunk| {
-            if chunk.is_empty() || t <= chunk[0].time {
-                return 0;
+                db = fixture.recover();
+                time = db.get_max_time();
             }
+        }
+    }
 
-            let chunk_sz = chunk.len();
-            let end      = if t >= chunk[chunk_sz - 1].time { chunk_sz } else { Self::binary_search(chunk.as_slice(), t) };
-            let cnt      = usize::min(end, max);
-            let start    = end - cnt;
+    ///Compares `aggregate()` with a naive fold over a plain vec. Chunks span a few
+    ///summary blocks, so that queries use the summaries of whole chunks and blocks,
+    ///and read the entries of those that are only partly inside [min; max] or
+    ///straddle a bucket edge. With a budget, the buckets before the returned time
+    ///must be complete, and resuming from it must visit every entry exactly once.
+    #[test]
+    fn buckets_match_naive_fold() {
+        for seed in 0..SEEDS {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let fixture = Fixture::new(&format!("buckets-{}", seed));
+            let mut db: MemDB<LitePlotData> = fixture.db();
+            let mut reference = Vec::new();
+            let mut time = 0;
+            let mut sealed = 0; //Entries in sealed chunks
+
+            for chunk in 0..rng.gen_range(2, 6) {
+                //The first chunk is partly inside the queries below
+                let count = if chunk == 0 { 2 * SUMMARY_BLOCK_SIZE + 7 } else { rng.gen_range(1, 3 * SUMMARY_BLOCK_SIZE) };
+
+                for _ in 0..count {
+                    time += rng.gen_range(0, 4); //Times repeat, including across blocks and chunks
+                    reference.push(entry(time, reference.len(), rng.gen_range(0, NAMES)));
+                }
 
-            for i in (start..end).rev() {
-                dst.push(chunk[i]);
+                db.extend(&reference[sealed..]);
+                fixture.seal(&mut db);
+                sealed = reference.len();
             }
 
-            cnt
-        }).unwrap_or(0)
-    }
-
-    fn query_right(&self, cid: usize, t: f64, max: usize, dst: &mut Vec<TimeData<T>>) -> usize where T: Copy {
-        self.with_chunk(cid, move |chunk| {
-            let chunk_sz = chunk.len();
-            if chunk_sz <= 0 || t >= chunk[chunk_sz - 1].time {
-                return 0;
+            //Left in the current chunk
+            for _ in 0..rng.gen_range(0, 100) {
+                time += rng.gen_range(0, 4);
+                reference.push(entry(time, reference.len(), rng.gen_range(0, NAMES)));
             }
 
-            let start = if t < chunk[0].time { 0 } else { Self::binary_search(chunk.as_slice(), t) };
-            let cnt   = usize::min(chunk_sz - start, max);
-            let end   = start + cnt;
+            db.extend(&reference[sealed..]);
+            db.flush();
 
-            for i in start..end {
-                dst.push(chunk[i]);
-            }
+            let accessor = db.new_accessor();
+            let last = reference.last().unwrap().time;
 
-            cnt
-        }).unwrap_or(0)
-    }
+            //A single bucket: whole chunks are summarized, and so are the blocks of
+            //the first one that are entirely after `min`
+            let min = reference[SUMMARY_BLOCK_SIZE / 2].time;
+            let mut entries = 0;
+            accessor.query_buckets(min, last, last - min + 1, None, |_, item| if let BucketItem::Entry(..) = item { entries += 1; });
 
-    pub fn query_count<Func: FnMut(&TimeData<T>)>(&self, t: f64, count: usize, mut callback: Func) where T: Copy {
-        let shared = self.contents.shared.read().unwrap();
-        let chunk_count = shared.old_chunks.len();
+            let first_block = reference[..SUMMARY_BLOCK_SIZE].iter().filter(|e| e.time >= min).count();
+            assert_eq!(entries, first_block + reference.len() - sealed, "seed {}: summaries of whole chunks and blocks aren't used", seed);
 
-        let first_chunk;
-        if chunk_count <= 0 || t <= shared.old_chunks[0].max {
-            first_chunk = 0;
-        } else if t > shared.old_chunks[chunk_count - 1].max {
-            first_chunk = chunk_count;
-        } else {
-            first_chunk = Self::binary_search_chunk(&shared.old_chunks, t);
-        }
+            for _ in 0..QUERIES {
+                let (a, b) = (random_time(&mut rng, &reference), random_time(&mut rng, &reference));
+                let (min, max) = (u64::min(a, b), u64::max(a, b));
 
-        drop(shared);
+                //Sometimes a divisor of the range, so that `max` falls on a bucket edge
+                let width = match rng.gen_range(0, 4) {
+                    0 => max - min + 1,
+                    1 => u64::max((max - min) / rng.gen_range(1, 8), 1),
+                    _ => rng.gen_range(1, 2 * SUMMARY_BLOCK_SIZE as u64)
+                };
 
-        let mut remaining_left = count / 2;
-        let mut remaining_right = count - remaining_left;
-        let mut left = Vec::with_capacity(remaining_left);
-        let mut right = Vec::with_capacity(remaining_right);
-        let mut cid = first_chunk;
-        let mut left_limit_hit = false;
+                //Not too many buckets, see `aggregate()`
+                let width = u64::max(width, (max - min) / 1000 + 1);
+                let expected = naive_aggregate(&reference, min, max, width);
 
-        if remaining_left > 0 {
-            loop {
-                remaining_left -= self.query_left(cid as usize, t, remaining_left, &mut left);
+                let (result, next) = accessor.aggregate(min, max, width, None, fold_plot_item);
+                assert_eq!(next, None, "seed {}: aggregate({}, {}, {}) is truncated without a budget", seed, min, max, width);
+                assert_eq!(result.len(), expected.len(), "seed {}: aggregate({}, {}, {}) bucket count", seed, min, max, width);
 
-                if remaining_left <= 0 {
-                    break;
+                for (i, (r, e)) in result.iter().zip(&expected).enumerate() {
+                    assert_eq!(bucket_values(r), bucket_values(e), "seed {}: aggregate({}, {}, {}), bucket {}", seed, min, max, width, i);
                 }
 
-                if cid <= 0 {
-                    left_limit_hit = true;
-                    break;
-                }
+                let limits = BudgetLimits {
+                    max_entries: Some(rng.gen_range(1, 2 * SUMMARY_BLOCK_SIZE)),
+                    max_chunks: Some(rng.gen_range(1, 3)),
+                    ..Default::default()
+                };
 
-                cid -= 1;
-            }
-        }
+                //Buckets that end before the returned time are complete. The last one includes `max`.
+                let (result, next) = accessor.aggregate(min, max, width, Some(&Budget::new(limits)), fold_plot_item);
 
-        remaining_right += remaining_left;
-        cid = first_chunk;
+                for (i, (r, e)) in result.iter().zip(&expected).enumerate() {
+                    if next.map(|next| i + 1 < result.len() && min + (i as u64 + 1) * width <= next).unwrap_or(true) {
+                        assert_eq!(bucket_values(r), bucket_values(e), "seed {}: truncated aggregate({}, {}, {}), bucket {} before {:?}", seed, min, max, width, i, next);
+                    }
+                }
 
-        while remaining_right > 0 && cid <= chunk_count {
-            remaining_right -= self.query_right(cid, t, remaining_right, &mut right);
-            cid += 1;
-        }
+                //Resumed from the time it returns until it's over, in a single bucket
+                let mut start = min;
+                let mut visited = PlotSummary::default();
 
-        if remaining_right > 0 && !left_limit_hit {
-            left.clear(); //We have no choice but the rebuild the entire list because we cannot resume the search
-            remaining_left = count - right.len();
-            cid = first_chunk;
+                loop {
+                    let mut part = PlotSummary::default();
+                    let next = accessor.query_buckets(start, max, max - min + 1, Some(&Budget::new(limits)), |_, item| fold_plot_item(&mut part, item));
+                    let end = match next {
+                        Some(next) => {
+                            assert!(next > start, "seed {}: query_buckets({}, {}) makes no progress from {}", seed, min, max, start);
+                            next - 1
+                        },
 
-            loop {
-                remaining_left -= self.query_left(cid as usize, t, remaining_left, &mut left);
+                        None => max
+                    };
 
-                if remaining_left <= 0 {
-                    break;
-                }
+                    //Nothing at or after the returned time was visited
+                    assert_eq!(bucket_values(&part), bucket_values(&naive_aggregate(&reference, start, end, max - min + 1)[0]), "seed {}: query_buckets({}, {}) resumed from {}", seed, min, max, start);
 
-                if cid <= 0 {
-                    break;
+                    for (&name, bucket) in &part {
+                        visited.entry(name).and_modify(|dst| dst.merge(bucket)).or_insert(*bucket);
+                    }
+
+                    match next {
+                        Some(next) => start = next,
+                        None       => break
+                    }
                 }
 
-                cid -= 1;
+                assert_eq!(bucket_values(&visited), bucket_values(&naive_aggregate(&reference, min, max, max - min + 1)[0]), "seed {}: resumed query_buckets({}, {})", seed, min, max);
             }
         }
-
-        for i in (0..left.len()).rev() {
-            callback(&left[i]);
-        }
-
-        for i in 0..right.len() {
-            callback(&right[i]);
-        }
     }
 
-    pub fn query_previous<Func: FnMut(&TimeData<T>)>(&self, t: f64, mut callback: Func) {
-        let shared = self.contents.shared.read().unwrap();
-        let chunk_count = shared.old_chunks.len();
-
-        let first_chunk;
-        if chunk_count <= 0 || t <= shared.old_chunks[0].max {
-            first_chunk = 0;
-        } else if t > shared.old_chunks[chunk_count - 1].max {
-            first_chunk = chunk_count;
-        } else {
-            first_chunk = Self::binary_search_chunk(&shared.old_chunks, t);
-        }
-
-        drop(shared);
-
-        let last_of_prev_chunk = self.with_chunk(first_chunk, |chunk| {
-            let chunk_sz = chunk.len();
-            if chunk_sz <= 0 || t < chunk[0].time || t >= chunk[chunk_sz - 1].time {
-                return false;
+    ///Iterates page by page, with limits and budgets, and checks that the pages
+    ///add up to what `query()` returns, and that resuming from any cursor returns
+    ///the rest of them. Also resumes from the first and past the last entry of
+    ///every chunk, current one included, and from cursors that were returned
+    ///before their chunks got dropped.
+    #[test]
+    fn cursors_resume_iterations() {
+        for seed in 0..SEEDS {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let fixture = Fixture::new(&format!("cursors-{}", seed));
+            let mut db: MemDB<LitePlotData> = fixture.db();
+            let mut reference = Vec::new();
+            let mut time = 0;
+
+            //Chunks of a few dozen to a few summary blocks, the last one stays current
+            db.set_seal_span(Some(rng.gen_range(50, 1000)));
+
+            for _ in 0..rng.gen_range(2000, 5000) {
+                time += rng.gen_range(0, 4);
+                //Some names are rare, so that whole chunks and blocks are skipped
+                let name = if rng.gen_range(0, 16) == 0 { rng.gen_range(1, NAMES) } else { 0 };
+                reference.push(entry(time, reference.len(), name));
             }
 
-            let i = Self::binary_search(chunk, t);
-
-            if i > 0 {
-                callback(&chunk[i - 1]);
-                false
-            } else {
-                true
-            }
-        }).unwrap_or(false);
+            db.extend(&reference);
+            db.flush();
 
-        if last_of_prev_chunk && first_chunk > 0 {
-            self.with_chunk(first_chunk - 1, move |chunk| {
-                let chunk_sz = chunk.len();
+            let mut old_cursors = Vec::new();
 
-                if chunk_sz > 0 {
-                    callback(&chunk[chunk_sz - 1]);
+            for round in 0..2 {
+                if round > 0 {
+                    assert!(db.drop_oldest_chunks(rng.gen_range(1, 3)) > 0, "seed {}: no chunk to drop", seed);
                 }
-            });
-        }
-    }
-
-    pub fn query<Func: FnMut(u64, &TimeData<T>)>(&self, min: f64, max: Option<f64>, mut callback: Func) {
-        let mut lookup_list = Vec::new(); //TODO: It sucks having to allocate everytime...
-        let (min, max, access) = self.prepare_query(min, max, &mut lookup_list);
 
-        for &i in &lookup_list {
-            let chunk = &access.old_chunks[i];
+                let accessor = db.new_accessor();
+                let mut all = Vec::new();
+                accessor.query(0, None, |k, e| all.push((k, *e)));
 
-            if let Some(data) = chunk.data.as_ref() {
-                let k_base = (i as u64) << 32;
-                let start = if chunk.min < min { Self::binary_search(data.as_slice(), min) } else { 0 };
+                let entries: Vec<_> = all.iter().map(|&(_, e)| e).collect();
+                let mut cursors = old_cursors.clone();
 
-                if chunk.max < max {
-                    for j in start..data.len() {
-                        callback(k_base | (j as u64), &data[j]);
+                //First entry of every chunk, and past its last one
+                for (i, &(k, _)) in all.iter().enumerate() {
+                    if all.get(i + 1).map(|&(next, _)| next >> 32 != k >> 32).unwrap_or(true) {
+                        cursors.push(k & !0xFFFF_FFFF);
+                        cursors.push(k + 1);
                     }
-                } else {
-                    for j in start..data.len() {
-                        let entry = &data[j];
-                        if entry.data.should_stop_query(entry.time, max) {
-                            return;
+                }
+
+                //In the current chunk
+                let last_cid = all.last().map(|&(k, _)| k >> 32).unwrap();
+                let current: Vec<_> = all.iter().filter(|&&(k, _)| k >> 32 == last_cid).collect();
+                cursors.push(current[rng.gen_range(0, current.len())].0);
+
+                for _ in 0..QUERIES {
+                    let (a, b) = (random_time(&mut rng, &entries), random_time(&mut rng, &entries));
+                    let (min, max) = (u64::min(a, b), u64::max(a, b));
+                    let keys = if rng.gen_range(0, 2) == 0 { None } else { Some(vec![rng.gen_range(0, NAMES)]) };
+                    let expected = naive_iter_from(&all, 0, min, max, &keys);
+
+                    let min_page = expected.len() / 32 + 1; //So that the test doesn't take forever
+
+                    //Sometimes exactly what's left of the first chunk, so that the first cursor is on a chunk boundary
+                    let limit = match (rng.gen_range(0, 4), expected.first()) {
+                        (0, Some(&(k, _))) => expected.iter().take_while(|&&(k2, _)| k2 >> 32 == k >> 32).count(),
+                        (1, _)             => min_page + rng.gen_range(0, 5),
+                        _                  => min_page + rng.gen_range(0, 200)
+                    };
+
+                    let limits = match rng.gen_range(0, 3) {
+                        0 => BudgetLimits { max_entries: Some(min_page + rng.gen_range(0, 100)), ..Default::default() },
+                        1 => BudgetLimits { max_chunks: Some(1), ..Default::default() },
+                        _ => BudgetLimits::default()
+                    };
+
+                    let (result, page_cursors) = paginate(&accessor, min, max, &keys, limit, limits);
+                    assert_eq!(result, expected, "seed {}, round {}: pages of [{}; {}] with {:?}, limit {}", seed, round, min, max, keys, limit);
+
+                    //A few of them, chunk boundaries are checked below
+                    for _ in 0..usize::min(page_cursors.len(), 4) {
+                        let cursor = page_cursors[rng.gen_range(0, page_cursors.len())];
+                        let mut iter = accessor.iter_from(cursor, Some(max));
+
+                        if let Some(keys) = &keys {
+                            iter = iter.with_keys(keys.clone());
                         }
 
-                        callback(k_base | (j as u64), entry);
+                        let result: Vec<_> = iter.map(|(k, e)| (k, e.data.value)).collect();
+                        assert_eq!(result, naive_iter_from(&all, cursor, min, max, &keys), "seed {}, round {}: [{}; {}] with {:?} resumed from {:x}", seed, round, min, max, keys, cursor);
+                        cursors.push(cursor);
                     }
                 }
-            } else {
-                warn!("Query will be incomplete since, for some reason, some chunk were unloaded");
+
+                cursors.sort_unstable();
+                cursors.dedup();
+
+                for &cursor in &cursors {
+                    let result: Vec<_> = accessor.iter_from(cursor, None).map(|(k, e)| (k, e.data.value)).collect();
+                    assert_eq!(result, naive_iter_from(&all, cursor, 0, u64::MAX, &None), "seed {}, round {}: resumed from {:x}", seed, round, cursor);
+                }
+
+                //Some of them point into the chunks dropped next round
+                old_cursors = cursors;
             }
         }
+    }
 
-        //If we haven't left this function by now, that means we also need to check the current chunk
-        let chunk = &access.current_chunk;
-        
-        if chunk.last().map(|c| min <= c.time).unwrap_or(false) {
-            let k_base = (access.old_chunks.len() as u64) << 32;
-            let start = if chunk[0].time < min { Self::binary_search(chunk.as_slice(), min) } else { 0 };
+    ///Fills the current chunk past its capacity while a reader holds it, and
+    ///checks that the reader still sees what was published, that entry IDs
+    ///don't change, and that the next chunk is sized after the sealed one.
+    #[test]
+    fn current_chunk_grows_under_readers() {
+        let fixture = Fixture::new("grow");
+        let mut db: MemDB<LitePlotData> = fixture.db();
+        let accessor = db.new_accessor();
+        let reference: Vec<_> = (0..4 * MIN_CURRENT_CAPACITY + 1).map(|i| entry(i as u64, i, 0)).collect();
+
+        assert_eq!(db.current_chunk.capacity(), MIN_CURRENT_CAPACITY);
+        db.extend(&reference[..MIN_CURRENT_CAPACITY]);
+        db.flush();
+        let snapshot = accessor.snapshot();
+
+        db.extend(&reference[MIN_CURRENT_CAPACITY..]);
+        db.flush();
+        assert_eq!(db.current_chunk.capacity(), 8 * MIN_CURRENT_CAPACITY);
+        assert_eq!(values(snapshot.current_chunk.as_slice()), values(&reference[..MIN_CURRENT_CAPACITY]));
+
+        let mut ids = Vec::new();
+        let mut all = Vec::new();
+        accessor.query(0, None, |k, e| {
+            ids.push(k);
+            all.push(*e);
+        });
+
+        assert_eq!(values(&all), values(&reference));
+        assert_eq!(ids, (0..reference.len() as u64).collect::<Vec<_>>());
+
+        assert!(fixture.seal(&mut db));
+        assert_eq!(db.current_chunk.capacity(), 8 * MIN_CURRENT_CAPACITY);
+        drop(snapshot);
+        drop(accessor);
+        drop(db);
+
+        let db: MemDB<LitePlotData> = fixture.recover();
+        assert_eq!(db.current_chunk.capacity(), 8 * MIN_CURRENT_CAPACITY);
+        assert_eq!(times(&db), reference.iter().map(|e| e.time).collect::<Vec<_>>());
+    }
 
-            for i in start..chunk.len() {
-                let entry = &chunk[i];
-                if entry.data.should_stop_query(entry.time, max) {
-                    break;
+    ///Pushes overlapping batches out of order, and checks that entries come out
+    ///sorted (in the order they were pushed in when they share a time), and that
+    ///those older than the published ones are counted as late.
+    #[test]
+    fn reorder_window_merges_batches() {
+        let fixture = Fixture::new("reorder");
+        let mut db: MemDB<LitePlotData> = fixture.db();
+        db.set_reorder_window(10);
+        db.extend(&[entry(5, 0, 0), entry(1, 1, 0), entry(8, 2, 0)]);
+        db.extend(&[entry(3, 3, 0), entry(5, 4, 0), entry(20, 5, 0)]);
+        db.extend(&[entry(4, 6, 0), entry(25, 7, 0)]);
+        db.flush();
+
+        let accessor = db.new_accessor();
+        let mut result = Vec::new();
+        accessor.query(0, None, |_, e| result.push(*e));
+
+        assert_eq!(values(&result), vec![(1, 1.0), (3, 3.0), (5, 0.0), (5, 4.0), (8, 2.0), (20, 5.0), (25, 7.0)]);
+        assert_eq!(accessor.get_late_count(), 1);
+    }
+
+    ///Pushes entries while several threads keep querying the whole MemDB, and
+    ///checks that each query sees a gap-free prefix of what was pushed, even
+    ///across chunk seals. Too slow to run every time, see `cargo test -- --ignored`.
+    #[test]
+    #[ignore]
+    fn bench_push_during_queries() {
+        let fixture = Fixture::new("bench");
+        let mut db: MemDB<LitePlotData> = fixture.db();
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let readers: Vec<_> = (0..READERS).map(|_| {
+            let accessor = db.new_accessor();
+            let stop = stop.clone();
+
+            thread::spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    let mut expected = 0;
+
+                    accessor.query(0, None, |k, entry| {
+                        assert_eq!(entry.data.value, expected as f64, "entry {:x} is not the one we expected", k);
+                        assert_eq!(k, (((expected / SWAP_THRESHOLD) as u64) << 32) | ((expected % SWAP_THRESHOLD) as u64));
+                        expected += 1;
+                    });
                 }
+            })
+        }).collect();
 
-                callback(k_base | (i as u64), entry);
-            }
+        let entries: Vec<_> = (0..ENTRIES).map(|i| TimeData {
+            time: i as u64,
+            data: LitePlotData::new(0, i as f64, 0)
+        }).collect();
+
+        for batch in entries.chunks(BATCH_SIZE) {
+            db.extend(batch);
+        }
+
+        stop.store(true, Ordering::Relaxed);
+
+        for reader in readers {
+            reader.join().unwrap();
         }
+
+        let accessor = db.new_accessor();
+        let mut count = 0;
+        accessor.query(0, None, |_, _| count += 1);
+
+        assert_eq!(count, ENTRIES);
+        assert_eq!(accessor.get_entry_count(), ENTRIES);
     }
 
-    pub fn get_max_time(&self) -> f64 {
-        self.contents.shared.read().unwrap().max
+    ///Corrupts the middle chunk of three, and checks that recovery keeps the
+    ///chunks after it, and that new chunks don't overwrite any of them.
+    #[test]
+    fn recovery_skips_corrupted_chunks() {
+        let fixture = Fixture::new("recovery");
+        let entries: Vec<_> = (0..40).map(|i| entry(i, i as usize, 0)).collect();
+
+        let mut db: MemDB<LitePlotData> = fixture.db();
+        db.set_seal_span(Some(9));
+        db.extend(&entries[..30]);
+        assert!(fixture.seal(&mut db));
+        drop(db);
+
+        //Flip a byte of the last entry of chunk 1, so that its checksum doesn't match
+        let mut bytes = fs::read(fixture.path("1")).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xFF;
+        fs::write(fixture.path("1"), &bytes).unwrap();
+        let chunk2 = fs::read(fixture.path("2")).unwrap();
+
+        let mut db: MemDB<LitePlotData> = fixture.recover();
+        let expected: Vec<u64> = (0..10).chain(20..30).collect();
+        assert_eq!(times(&db), expected);
+
+        let health = db.new_accessor().get_health();
+        assert_eq!(health.len(), 1);
+        assert_eq!((health[0].chunk, health[0].min, health[0].max, health[0].count), (1, 10, 19, 10));
+
+        db.set_seal_span(Some(9));
+        db.extend(&entries[30..]);
+        assert!(fixture.seal(&mut db));
+        drop(db);
+
+        assert_eq!(fs::read(fixture.path("1")).unwrap(), bytes);
+        assert_eq!(fs::read(fixture.path("2")).unwrap(), chunk2);
+
+        let db: MemDB<LitePlotData> = fixture.recover();
+        let expected: Vec<u64> = (0..10).chain(20..40).collect();
+        assert_eq!(times(&db), expected);
+        assert_eq!(db.new_accessor().get_health().len(), 1);
     }
 
-    pub fn get_stats(&self) -> (usize, usize) {
-        let loaded = self.contents.loaded_chunks.lock().unwrap().len() + 1;
-        let total = self.contents.shared.read().unwrap().old_chunks.len() + 1;
+    ///Corrupts and truncates chunk files while they're mapped, and checks that
+    ///their entries go missing instead of being read, and that they're reported.
+    #[test]
+    fn broken_chunk_files_are_reported() {
+        let fixture = Fixture::new("broken");
+        let entries: Vec<_> = (0..30).map(|i| entry(i, i as usize, 0)).collect();
+
+        let mut db: MemDB<LitePlotData> = fixture.db();
+        db.set_seal_span(Some(9));
+        db.extend(&entries);
+        assert!(fixture.seal(&mut db));
+
+        //Flip a byte of the last entry of chunk 0, and cut chunk 1 in half
+        let mut bytes = fs::read(fixture.path("0")).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xFF;
+        fs::write(fixture.path("0"), &bytes).unwrap();
+
+        let file = fs::OpenOptions::new().write(true).open(fixture.path("1")).unwrap();
+        let len = file.metadata().unwrap().len();
+        file.set_len(len / 2).unwrap();
+        drop(file);
+
+        let accessor = db.new_accessor();
+        let mut times = Vec::new();
+        accessor.query(0, None, |_, e| times.push(e.time));
+        assert_eq!(times, (20..30).collect::<Vec<u64>>());
 
-        (loaded, total)
+        let health: Vec<_> = accessor.get_health().into_iter().map(|f| (f.chunk, f.min, f.max, f.count)).collect();
+        assert_eq!(health, vec![(0, 0, 9, 10), (1, 10, 19, 10)]);
     }
-}
 
-impl<T> Clone for Accessor<T> {
-    fn clone(&self) -> Self {
-        Self {
-            contents: self.contents.clone()
+    ///Removes chunk 1 of 4 from the manifest, and checks that recovery keeps the
+    ///chunks after it, including one the manifest doesn't list at all, and that
+    ///new chunks don't overwrite any of them.
+    #[test]
+    fn recovery_keeps_chunks_after_missing_ids() {
+        let fixture = Fixture::new("missing");
+        let entries: Vec<_> = (0..50).map(|i| entry(i, i as usize, 0)).collect();
+
+        let mut db: MemDB<LitePlotData> = fixture.db();
+        db.set_seal_span(Some(9));
+        db.extend(&entries[..40]);
+        assert!(fixture.seal(&mut db));
+        drop(db);
+
+        //Chunk 1 is missing from the manifest, chunk 3 was written but the manifest wasn't updated
+        let manifest: Vec<ManifestEntry<u64>> = bincode::deserialize(&fs::read(fixture.path(MANIFEST_FILE)).unwrap()).unwrap();
+        let manifest: Vec<_> = manifest.into_iter().filter(|entry| entry.id != 1 && entry.id != 3).collect();
+        fs::write(fixture.path(MANIFEST_FILE), bincode::serialize(&manifest).unwrap()).unwrap();
+        let chunks: Vec<_> = (1..4).map(|i| fs::read(fixture.path(&i.to_string())).unwrap()).collect();
+
+        let mut db: MemDB<LitePlotData> = fixture.recover();
+        let expected: Vec<u64> = (0..10).chain(20..30).collect();
+        assert_eq!(times(&db), expected);
+
+        let health: Vec<_> = db.new_accessor().get_health().into_iter().map(|f| f.chunk).collect();
+        assert_eq!(health, vec![1, 3]);
+
+        db.set_seal_span(Some(9));
+        db.extend(&entries[40..]);
+        assert!(fixture.seal(&mut db));
+        drop(db);
+
+        for i in 1..4 {
+            assert_eq!(fs::read(fixture.path(&i.to_string())).unwrap(), chunks[i - 1], "chunk {} was overwritten", i);
         }
+
+        let db: MemDB<LitePlotData> = fixture.recover();
+        let expected: Vec<u64> = (0..10).chain(20..30).chain(40..50).collect();
+        assert_eq!(times(&db), expected);
+        assert_eq!(db.new_accessor().get_health().len(), 2);
     }
-}
 
-///Initializes the MemDB module
-///
-///Unsafe because it is the user's responsibility to call this
-///method only once and before any call to `MemDB::new()`
-pub unsafe fn init()
-{
-    START_INSTANT.write(Instant::now());
+    ///Zone chunks are stored column by column: checks that their files hold
+    ///nothing but the columns, and that entries are rebuilt from them as-is,
+    ///before and after a reload.
+    #[test]
+    fn zone_chunks_only_store_columns() {
+        let fixture = Fixture::new("columns");
+        let count = SUMMARY_BLOCK_SIZE + 3;
+        let zones: Vec<_> = (0..count).map(|i| TimeData {
+            time: 10 * i as u64,
+            data: LiteZoneData { uid: i, duration: (i % 7) as u64, name: i % 3, thread: i % 2, color: i as u32, depth: (i % 5) as u32 }
+        }).collect();
+
+        let check = |db: &MemDB<LiteZoneData>| {
+            let accessor = db.new_accessor();
+            let mut all = Vec::new();
+            accessor.query(0, None, |_, z| all.push((z.time, z.data.uid, z.data.duration, z.data.name, z.data.thread, z.data.color, z.data.depth)));
+
+            let expected: Vec<_> = zones.iter().map(|z| (z.time, z.data.uid, z.data.duration, z.data.name, z.data.thread, z.data.color, z.data.depth)).collect();
+            assert_eq!(all, expected);
+
+            let mut durations = Vec::new();
+            assert_eq!(accessor.scan_columns(15, 95, None, |view| durations.extend_from_slice(view.column::<u64>(LiteZoneData::DURATION_COLUMN))), None);
+            assert_eq!(durations, (2..10).map(|i| (i % 7) as u64).collect::<Vec<_>>());
+        };
+
+        let mut db: MemDB<LiteZoneData> = fixture.db();
+        db.extend(&zones);
+        assert!(fixture.seal(&mut db));
+        check(&db);
+        drop(db);
+
+        //The header, then the times and each column, 8-byte aligned. The count is odd, so the color column needs padding.
+        let expected = std::iter::once(8).chain(LiteZoneData::COLUMNS.iter().cloned()).fold(CHUNK_HEADER_SIZE, |offset, width| ((offset + 7) & !7) + width * count);
+        let size = fs::metadata(fixture.path("0")).unwrap().len() as usize;
+        assert_eq!(size, expected);
+        assert_eq!(expected, CHUNK_HEADER_SIZE + count * std::mem::size_of::<TimeData<LiteZoneData>>() + 4);
+
+        let db: MemDB<LiteZoneData> = fixture.recover();
+        assert!(db.new_accessor().get_health().is_empty());
+        check(&db);
+    }
+
+    ///Checks that the current chunk is sealed once the clock of the context
+    ///says it has been idle for long enough, and not before.
+    #[test]
+    fn seal_if_idle_follows_context_clock() {
+        let fixture = Fixture::new("idle");
+        let clock = &fixture.clock;
+        let mut db: MemDB<LitePlotData> = fixture.db();
+        let accessor = db.new_accessor();
+
+        db.set_seal_idle(Some(Duration::from_secs(60)));
+        db.extend(&[TimeData { time: 1, data: LitePlotData::new(0, 1.0, 0) }]);
+
+        clock.advance(Duration::from_secs(59));
+        assert!(!db.seal_if_idle());
+        assert_eq!(accessor.get_stats().1, 1);
+
+        //Pushing again resets the delay
+        db.extend(&[TimeData { time: 2, data: LitePlotData::new(0, 2.0, 0) }]);
+        clock.advance(Duration::from_secs(59));
+        assert!(!db.seal_if_idle());
+
+        clock.advance(Duration::from_secs(1));
+        assert!(db.seal_if_idle());
+        assert_eq!(accessor.get_stats().1, 2);
+        assert_eq!(accessor.get_entry_count(), 2);
+
+        //Nothing left to seal
+        clock.advance(Duration::from_secs(120));
+        assert!(!db.seal_if_idle());
+    }
 }
@@ -1,7 +1,8 @@
 use crate::string_collection::{StringCollection, Key as SCKey};
+use crate::catalogue::{Catalogue, PlotSeries, ThreadInfo};
 use crate::stoppable_thread::StoppableThread;
-use crate::memdb::{TimeData, MemDB};
-use crate::common::{LiteZoneData, LitePlotData};
+use crate::memdb::{TimeData, MemDB, BucketItem};
+use crate::common::{LiteFrameData, LiteZoneData, LitePlotData, PlotBucket, PlotSummary, ZoneKey, to_nanos};
 
 use std::time::{Instant, Duration};
 use std::boxed::Box;
@@ -9,18 +10,34 @@ use std::mem::MaybeUninit;
 use std::sync::atomic::{AtomicU64, Ordering};
 
 use temporal_lens::shmem::{self, SharedMemory, FrameData, ZoneData, PlotData};
+use fxhash::{FxHashMap, FxHashSet};
 use log::{info, warn};
 
 static POLLER: StoppableThread = StoppableThread::new("shmem_poller");
 static LAST_QUERY: AtomicU64 = AtomicU64::new(0);
+const RETENTION_PERIOD: Duration = Duration::from_secs(1); //How often the retention policy is enforced
 
-pub fn start(mut shmem: SharedMemory, opt_start: Option<Instant>, mut str_collection: StringCollection, mut frame_db: MemDB<FrameData>, mut zone_db: MemDB<LiteZoneData>, mut plot_db: MemDB<LitePlotData>) {
+///Limits on how much of the capture is kept. When exceeded, the oldest
+///sealed chunks of each MemDB are dropped.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Retention
+{
+    pub frame_seconds: Option<f64>, //Maximum age of an entry of each MemDB, relative to the latest one of any of them
+    pub zone_seconds: Option<f64>,
+    pub plot_seconds: Option<f64>,
+    pub bytes: Option<u64> //Maximum size of the sealed chunks of all MemDBs together, see `MemDB::get_stored_bytes()`
+}
+
+pub fn start(mut shmem: SharedMemory, opt_start: Option<Instant>, retention: Retention, mut str_collection: StringCollection, mut plot_catalogue: Catalogue<PlotSeries>, mut thread_catalogue: Catalogue<ThreadInfo>, mut frame_db: MemDB<LiteFrameData>, mut zone_db: MemDB<LiteZoneData>, mut plot_db: MemDB<LitePlotData>) {
     POLLER.start(move || {
         let mut frame_data: Box<MaybeUninit<[FrameData; shmem::NUM_ENTRIES]>> = Box::new_uninit();
         let mut zone_data: Box<MaybeUninit<[ZoneData; shmem::NUM_ENTRIES]>> = Box::new_uninit();
         let mut plot_data: Box<MaybeUninit<[PlotData; shmem::NUM_ENTRIES]>> = Box::new_uninit();
-        let mut last_time: shmem::Time = 0.0;
+        let mut frame_batch = Vec::with_capacity(shmem::NUM_ENTRIES);
+        let mut zone_batch = Vec::with_capacity(shmem::NUM_ENTRIES);
+        let mut plot_batch = Vec::with_capacity(shmem::NUM_ENTRIES);
         let mut counter = 0;
+        let mut last_retention = Instant::now();
 
         while POLLER.running() {
             let mut total_data_retrieved = 0;
@@ -43,15 +60,16 @@ pub fn start(mut shmem: SharedMemory, opt_start: Option<Instant>, mut str_collec
                 warn!("Server is too slow! Missed {} FrameData entries!", missed);
             }
 
-            for i in 0..count {
-                let fdi = &fd[i];
-
-                frame_db.push(TimeData {
-                    time: fdi.end,
-                    data: *fdi
-                });
-            }
+            frame_batch.clear();
+            frame_batch.extend(fd[..count].iter().map(|fdi| TimeData {
+                time: to_nanos(fdi.end),
+                data: LiteFrameData {
+                    number  : fdi.number,
+                    duration: fdi.duration
+                }
+            }));
 
+            frame_db.extend(&frame_batch);
             total_data_retrieved += count;
 
             //================= ZONES =================//
@@ -64,19 +82,24 @@ pub fn start(mut shmem: SharedMemory, opt_start: Option<Instant>, mut str_collec
                 warn!("Server is too slow! Missed {} ZoneData entries!", missed);
             }
 
+            zone_batch.clear();
+
             for i in 0..count {
                 let zdi = &zd[i];
+                let zone_end = to_nanos(zdi.end);
+                let zone_start = zone_end.saturating_sub(zdi.duration);
 
-                if let Some(s) = zdi.name.make_str() {
-                    str_collection.insert(SCKey::StaticString(zdi.name.get_key()), s);
+                //Strings come from another process: their bytes are only trusted once validated, see `insert_bytes()`
+                if let Some(s) = zdi.name.make_bytes() {
+                    str_collection.insert_bytes(SCKey::StaticString(zdi.name.get_key()), s, zone_start);
                 }
 
-                if let Some(s) = zdi.thread.make_str() {
-                    str_collection.insert(SCKey::ThreadName(zdi.thread.get_key()), s);
+                if let Some(s) = zdi.thread.make_bytes() {
+                    str_collection.insert_bytes(SCKey::ThreadName(zdi.thread.get_key()), s, zone_start);
                 }
 
-                let entry = TimeData {
-                    time: if zdi.end < last_time { last_time } else { zdi.end },
+                zone_batch.push(TimeData {
+                    time: zone_end,
                     data: LiteZoneData {
                         uid     : zdi.uid,
                         color   : zdi.color,
@@ -85,12 +108,10 @@ pub fn start(mut shmem: SharedMemory, opt_start: Option<Instant>, mut str_collec
                         name    : zdi.name.get_key(),
                         thread  : zdi.thread.get_key()
                     }
-                };
-
-                last_time = zdi.end;
-                zone_db.push(entry); //Is that good or is it better to do it all at once?
+                });
             }
 
+            push_zones(&mut thread_catalogue, &mut zone_db, &zone_batch);
             total_data_retrieved += count;
 
             //================= PLOTS =================//
@@ -103,29 +124,48 @@ pub fn start(mut shmem: SharedMemory, opt_start: Option<Instant>, mut str_collec
                 warn!("Server is too slow! Missed {} PlotData entries!", missed);
             }
 
+            plot_batch.clear();
+
             for i in 0..count {
                 let pdi = &pd[i];
+                let time = to_nanos(pdi.time);
 
-                if let Some(s) = pdi.name.make_str() {
-                    str_collection.insert(SCKey::StaticString(pdi.name.get_key()), s);
+                if let Some(s) = pdi.name.make_bytes() {
+                    str_collection.insert_bytes(SCKey::StaticString(pdi.name.get_key()), s, time);
                 }
 
-                plot_db.push(TimeData {
-                    time: pdi.time,
-                    data: LitePlotData {
-                        color: pdi.color,
-                        value: pdi.value,
-                        name : pdi.name.get_key()
-                    }
+                plot_batch.push(TimeData {
+                    time,
+                    data: LitePlotData::new(pdi.color, pdi.value, pdi.name.get_key())
                 });
             }
 
+            push_plots(&mut plot_catalogue, &mut plot_db, &plot_batch);
             total_data_retrieved += count;
-            frame_db.unload_old_chunks();
-            zone_db.unload_old_chunks();
-            plot_db.unload_old_chunks();
-            
-            if total_data_retrieved <= 0 {
+
+            if total_data_retrieved > 0 {
+                if let Err(err) = str_collection.flush_log() {
+                    warn!("Failed to flush string log: {:?}", err);
+                }
+            }
+
+            if last_retention.elapsed() >= RETENTION_PERIOD {
+                last_retention = Instant::now();
+
+                enforce_retention(retention, &mut str_collection, &mut plot_catalogue, &mut thread_catalogue, &mut frame_db, &mut zone_db, &mut plot_db);
+            }
+
+            //A stream may be idle while others aren't
+            frame_db.seal_if_idle();
+            zone_db.seal_if_idle();
+            plot_db.seal_if_idle();
+
+            if total_data_retrieved == 0 {
+                //Nothing is coming in, no need to wait for late entries
+                frame_db.flush();
+                zone_db.flush();
+                plot_db.flush();
+
                 std::thread::sleep(Duration::from_millis(10));
                 counter = 0;
             } else {
@@ -140,6 +180,188 @@ pub fn start(mut shmem: SharedMemory, opt_start: Option<Instant>, mut str_collec
     });
 }
 
+///Pushes `batch` into `zone_db`, and records the zones it accepts in `thread_catalogue`.
+///Late zones are dropped by `MemDB::extend()`, so they must not be counted.
+pub fn push_zones(thread_catalogue: &mut Catalogue<ThreadInfo>, zone_db: &mut MemDB<LiteZoneData>, batch: &[TimeData<LiteZoneData>]) {
+    for r in batch.iter().filter(|r| zone_db.accepts(r.time)) {
+        let start = r.time.saturating_sub(r.data.duration);
+        thread_catalogue.record(r.data.thread, || ThreadInfo::new(start, r.time), |t| t.add(start, r.time));
+    }
+
+    zone_db.extend(batch);
+}
+
+///Same as `push_zones()`, for plot samples
+pub fn push_plots(plot_catalogue: &mut Catalogue<PlotSeries>, plot_db: &mut MemDB<LitePlotData>, batch: &[TimeData<LitePlotData>]) {
+    for r in batch.iter().filter(|r| plot_db.accepts(r.time)) {
+        plot_catalogue.record(r.data.name, || PlotSeries::new(r.time, r.data.color, r.data.value), |s| s.add(r.time, r.data.color, r.data.value));
+    }
+
+    plot_db.extend(batch);
+}
+
+///Drops the chunks that exceed `retention`, then updates the statistics of
+///threads and plot series, and forgets those (and the strings) that only
+///appeared in them.
+fn enforce_retention(retention: Retention, str_collection: &mut StringCollection, plot_catalogue: &mut Catalogue<PlotSeries>, thread_catalogue: &mut Catalogue<ThreadInfo>, frame_db: &mut MemDB<LiteFrameData>, zone_db: &mut MemDB<LiteZoneData>, plot_db: &mut MemDB<LitePlotData>) {
+    let mut dropped = 0;
+
+    let latest = u64::max(frame_db.get_max_time(), u64::max(zone_db.get_max_time(), plot_db.get_max_time()));
+    let cutoff = |seconds: Option<f64>| seconds.map(|s| latest.saturating_sub(to_nanos(s)));
+
+    if let Some(t) = cutoff(retention.frame_seconds) {
+        dropped += frame_db.drop_chunks_before(t);
+    }
+
+    if let Some(t) = cutoff(retention.zone_seconds) {
+        dropped += zone_db.drop_chunks_before(t);
+    }
+
+    if let Some(t) = cutoff(retention.plot_seconds) {
+        dropped += plot_db.drop_chunks_before(t);
+    }
+
+    if let Some(bytes) = retention.bytes {
+        while frame_db.get_stored_bytes() + zone_db.get_stored_bytes() + plot_db.get_stored_bytes() > bytes {
+            //Drop the oldest chunk across all MemDBs, so that they cover roughly the same time range
+            let ends = [frame_db.get_oldest_chunk_end(), zone_db.get_oldest_chunk_end(), plot_db.get_oldest_chunk_end()];
+            let oldest = (0..ends.len()).filter_map(|i| ends[i].map(|t| (i, t))).min_by_key(|&(_, t)| t);
+
+            dropped += match oldest {
+                Some((0, _)) => frame_db.drop_oldest_chunks(1),
+                Some((1, _)) => zone_db.drop_oldest_chunks(1),
+                Some(_)      => plot_db.drop_oldest_chunks(1),
+                None         => break //Only the current chunks are left
+            };
+        }
+    }
+
+    if dropped == 0 {
+        return;
+    }
+
+    let threads = thread_stats(zone_db);
+    let series = plot_stats(plot_db);
+    let mut zone_names = FxHashSet::default();
+
+    zone_db.for_each_key(|k| if let ZoneKey::Name(name) = k {
+        zone_names.insert(name);
+    });
+
+    let removed_threads = thread_catalogue.retain(|key, t| match threads.get(&key) {
+        Some(info) => {
+            *t = *info;
+            true
+        },
+        None => false
+    });
+
+    let removed_series = plot_catalogue.retain(|key, s| match series.get(&key) {
+        Some(bucket) => {
+            s.count = bucket.count;
+            s.min   = bucket.min;
+            s.max   = bucket.max;
+            s.first = bucket.first_time;
+            s.last  = bucket.last_time;
+            true
+        },
+        None => false
+    });
+
+    let strings = str_collection.forget_before(u64::min(zone_db.get_min_time(), plot_db.get_min_time()), |k| match k {
+        SCKey::ThreadName(key)   => !threads.contains_key(&key),
+        SCKey::StaticString(key) => !zone_names.contains(&key) && !series.contains_key(&key)
+    });
+
+    info!("Retention policy dropped {} chunks, {} threads, {} plot series and {} strings", dropped, removed_threads.len(), removed_series.len(), strings);
+}
+
+///Fills the catalogues from MemDBs that were just recovered (see `MemDB::recover()`).
+///Just like retention, relies on chunk summaries, so that the whole capture doesn't
+///have to be read. Only the last sample of each plot series is, for its color.
+pub fn recover_catalogues(plot_catalogue: &mut Catalogue<PlotSeries>, thread_catalogue: &mut Catalogue<ThreadInfo>, zone_db: &MemDB<LiteZoneData>, plot_db: &MemDB<LitePlotData>) {
+    let accessor = plot_db.new_accessor();
+
+    for (thread, info) in thread_stats(zone_db) {
+        thread_catalogue.record(thread, || info, |t| *t = info);
+    }
+
+    for (name, bucket) in plot_stats(plot_db) {
+        let mut color = 0;
+        accessor.query_previous_matching(bucket.last_time.saturating_add(1), bucket.last_time, &[name], None, |r| color = r.data.color);
+
+        let series = PlotSeries {
+            color,
+            count: bucket.count,
+            min  : bucket.min,
+            max  : bucket.max,
+            first: bucket.first_time,
+            last : bucket.last_time
+        };
+
+        plot_catalogue.record(name, || series, |s| *s = series);
+    }
+}
+
+///Recomputes the statistics of every thread from the zones that weren't dropped.
+///Relies on chunk summaries, so that sealed chunks aren't read from disk.
+fn thread_stats(zone_db: &MemDB<LiteZoneData>) -> FxHashMap<usize, ThreadInfo> {
+    let mut ret: FxHashMap<usize, ThreadInfo> = Default::default();
+
+    let add = |dst: &mut FxHashMap<usize, ThreadInfo>, thread: usize, info: ThreadInfo| {
+        dst.entry(thread).and_modify(|t| t.merge(&info)).or_insert(info);
+    };
+
+    let add_zone = |dst: &mut FxHashMap<usize, ThreadInfo>, entry: &TimeData<LiteZoneData>| {
+        add(dst, entry.data.thread, ThreadInfo::new(entry.time.saturating_sub(entry.data.duration), entry.time));
+    };
+
+    zone_db.new_accessor().query_buckets(0, u64::MAX, u64::MAX, None, |_, item| match item {
+        BucketItem::Summary(_, summary) => {
+            for (&(thread, _), span) in &summary.spans {
+                add(&mut ret, thread, ThreadInfo { first: span.start, last: span.end, zones: span.count });
+            }
+
+            for (_, entry) in &summary.long {
+                add_zone(&mut ret, entry);
+            }
+        },
+
+        BucketItem::Entry(_, entry) => add_zone(&mut ret, entry)
+    });
+
+    for entry in zone_db.get_pending() {
+        add_zone(&mut ret, entry);
+    }
+
+    ret
+}
+
+///Same as `thread_stats()`, for plot series
+fn plot_stats(plot_db: &MemDB<LitePlotData>) -> PlotSummary {
+    let mut ret = PlotSummary::default();
+
+    let add = |dst: &mut PlotSummary, name: usize, src: &PlotBucket| {
+        dst.entry(name).and_modify(|x| x.merge(src)).or_insert(*src);
+    };
+
+    plot_db.new_accessor().query_buckets(0, u64::MAX, u64::MAX, None, |_, item| match item {
+        BucketItem::Summary(_, summary) => {
+            for (&name, src) in summary {
+                add(&mut ret, name, src);
+            }
+        },
+
+        BucketItem::Entry(_, r) => add(&mut ret, r.data.name, &PlotBucket::new(r.time, r.data.value))
+    });
+
+    for r in plot_db.get_pending() {
+        add(&mut ret, r.data.name, &PlotBucket::new(r.time, r.data.value));
+    }
+
+    ret
+}
+
 pub fn stop() -> bool {
     POLLER.stop()
 }
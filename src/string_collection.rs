@@ -1,13 +1,24 @@
 use std::mem::MaybeUninit;
 use std::sync::{RwLock, Arc};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::ops::Index;
 use std::cell::UnsafeCell;
+use std::borrow::Cow;
+use std::path::{Path, PathBuf};
+use std::ffi::OsString;
+use std::io::{BufReader, BufWriter, Read, Write, Error as IOResult, ErrorKind as IOErrorKind};
+use std::fs;
 
 use fxhash::FxHashMap;
+use serde::{Serialize, Deserialize};
+use bincode::{Error as BincodeError, ErrorKind as BincodeErrorKind};
+use log::{warn, debug};
 
 const POOL_SIZE: usize = 8192;
+const LARGE_STRING_SIZE: usize = POOL_SIZE / 4; //Strings this long get their own allocation
+const MAX_STRING_SIZE: usize = 1 << 20;         //Longer strings are rejected
 
-#[derive(Debug, Copy, Clone, Hash, Eq, PartialEq)]
+#[derive(Debug, Copy, Clone, Hash, Eq, PartialEq, Serialize, Deserialize)]
 pub enum Key
 {
     StaticString(usize),
@@ -21,39 +32,279 @@ struct Entry
     len: usize
 }
 
+///A string along with the time from which it is valid
+#[derive(Copy, Clone)]
+struct Version
+{
+    since: u64, //In nanoseconds
+    entry: Entry
+}
+
 struct Pool
 {
     bytes: Box<MaybeUninit<[u8; POOL_SIZE]>>,
     pos: usize
 }
 
+#[derive(Default)]
+struct Metrics
+{
+    large: AtomicU64,
+    invalid_utf8: AtomicU64,
+    rejected: AtomicU64
+}
+
+#[derive(Debug, Copy, Clone, Serialize)]
+pub struct Stats
+{
+    pub strings: usize,
+    pub large: u64,
+    pub invalid_utf8: u64,
+    pub rejected: u64
+}
+
 struct Internal
 {
-    map: RwLock<FxHashMap<Key, Entry>>,
-    pools: UnsafeCell<Vec<Pool>>
+    map: RwLock<FxHashMap<Key, Vec<Version>>>, //Sorted by `since`
+    pools: UnsafeCell<Vec<Pool>>,
+    large: UnsafeCell<Vec<Box<[u8]>>>,
+    metrics: Metrics
+}
+
+///File strings are appended to as soon as they are inserted, see `StringCollection::open_log()`
+struct StringLog
+{
+    writer: BufWriter<fs::File>,
+    path: PathBuf
+}
+
+///Counts the bytes read, so that we know where the last complete record of a string log ends
+struct CountingReader<R>
+{
+    inner: R,
+    count: u64
 }
 
 pub struct Accessor(Arc<Internal>);
-pub struct StringCollection(Arc<Internal>);
+pub struct StringCollection(Arc<Internal>, Option<StringLog>); //The second field is the string log, if any
+
+#[derive(Debug)]
+pub enum LogError
+{
+    FileCreateError(IOResult),
+    FileOpenError(IOResult),
+    SerializeError(BincodeError),
+    DeserializeError(BincodeError),
+    FlushError(IOResult),
+    SyncError(IOResult),
+    TruncateError(IOResult),
+    RenameError(IOResult)
+}
 
 ///A `StringCollection` is a map of read-only strings,
 ///indexed by a `usize` key.
 ///
 ///It was specifically designed for the needs of
 ///`temporal-lens-server` and thus, strings can only
-///be inserted from one thread and never removed or
-///updated afterwards. The benefit is that the strings
-///can be read from any thread as long as `Accessor`
-///exists.
+///be inserted from one thread. They may be forgotten
+///(see `forget_before()`) but their memory is never
+///freed. The benefit is that the strings can be read
+///from any thread as long as `Accessor` exists.
+///
+///Keys are typically string addresses in the profiled
+///process, which may be reused (or a thread may be
+///renamed). Thus, each key maps to a list of versions,
+///each one valid from the time it was inserted at, and
+///`Accessor::get_at()` returns the version that was
+///valid at a given time.
+///
+///Since strings come from the process being profiled,
+///they are not trusted: invalid UTF-8 sequences are
+///replaced and strings longer than `MAX_STRING_SIZE`
+///are rejected. Both are reported in `Stats`.
+///
+///The collection can be persisted as a string log, that
+///is a file to which every (key, time, string) tuple is appended
+///as soon as it is inserted (see `open_log()`). Reading
+///this log back with `load()` yields the same collection,
+///and `recover_log()` resumes logging into it. Forgetting
+///strings rewrites the log, so that it doesn't keep growing.
 impl StringCollection
 {
     pub fn new() -> Self {
         let ret = Internal {
             map: RwLock::new(Default::default()),
-            pools: UnsafeCell::new(Vec::new())
+            pools: UnsafeCell::new(Vec::new()),
+            large: UnsafeCell::new(Vec::new()),
+            metrics: Default::default()
+        };
+
+        Self(Arc::new(ret), None)
+    }
+
+    ///Rebuilds a collection from a string log written by `open_log()` or `save_to()`.
+    ///A truncated last record (e.g. if the server crashed) is ignored.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, LogError> {
+        let file = fs::File::open(path).map_err(LogError::FileOpenError)?;
+        let mut reader = CountingReader { inner: BufReader::new(file), count: 0 };
+
+        match Self::read_log(&mut reader) {
+            (ret, None)    => Ok(ret),
+            (_, Some(err)) => Err(LogError::DeserializeError(err))
+        }
+    }
+
+    ///Rebuilds a collection from the string log at `path`, just like `load()`,
+    ///and keeps logging strings into it. A truncated last record is cut off, so
+    ///that new records can be appended. If the log is corrupted, the strings
+    ///before the first bad record are kept, and the log is moved aside (to
+    ///`<path>.corrupted`) and replaced by a new one. If there's no log, a new
+    ///one is created.
+    pub fn recover_log<P: AsRef<Path>>(path: P) -> Result<Self, LogError> {
+        let path = path.as_ref();
+        let file = match fs::File::open(path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == IOErrorKind::NotFound => {
+                let mut ret = Self::new();
+                ret.open_log(path)?;
+
+                return Ok(ret);
+            },
+            Err(err) => return Err(LogError::FileOpenError(err))
         };
 
-        Self(Arc::new(ret))
+        let mut reader = CountingReader { inner: BufReader::new(file), count: 0 };
+        let (mut ret, err) = Self::read_log(&mut reader);
+        let complete = reader.count;
+        let file = reader.inner.into_inner();
+
+        if let Some(err) = err {
+            let aside = suffixed(path, ".corrupted");
+            warn!("String log is corrupted after {} bytes ({}), moving it to \"{}\"", complete, err, aside.to_string_lossy());
+            drop(file);
+
+            if let Err(err) = fs::rename(path, &aside) {
+                //Better not persist strings at all than overwrite the ones we couldn't read
+                warn!("Failed to move corrupted string log aside: {}. Strings will not be persisted.", err);
+                return Ok(ret);
+            }
+
+            ret.open_log(path)?;
+            return Ok(ret);
+        }
+
+        let len = file.metadata().map_err(LogError::FileOpenError)?.len();
+        drop(file);
+
+        let file = fs::OpenOptions::new().append(true).open(path).map_err(LogError::FileOpenError)?;
+
+        if complete < len {
+            warn!("Cutting off the last {} bytes of the string log, which are an incomplete record", len - complete);
+            file.set_len(complete).map_err(LogError::TruncateError)?;
+        }
+
+        ret.1 = Some(StringLog { writer: BufWriter::new(file), path: path.to_path_buf() });
+        Ok(ret)
+    }
+
+    ///Reads string log records until the end of `reader`, or until one of them
+    ///can't be read. Returns the collection along with the error that stopped
+    ///the reading, if it isn't just a truncated last record. `reader.count`
+    ///then tells where the last complete record ends.
+    fn read_log<R: Read>(reader: &mut CountingReader<R>) -> (Self, Option<BincodeError>) {
+        let mut ret = Self::new();
+        let mut complete = 0;
+        let mut error = None;
+
+        loop {
+            match bincode::deserialize_from::<_, (Key, u64, String)>(&mut *reader) {
+                Ok((k, t, v)) => {
+                    ret.insert(k, &v, t);
+                    complete = reader.count;
+                },
+                Err(err) => {
+                    match *err {
+                        BincodeErrorKind::Io(ref io_err) if io_err.kind() == IOErrorKind::UnexpectedEof => {},
+                        _ => error = Some(err)
+                    }
+
+                    break;
+                }
+            }
+        }
+
+        reader.count = complete;
+        debug!("Loaded {} strings from string log", ret.0.map.read().unwrap().len());
+
+        (ret, error)
+    }
+
+    ///Writes the whole collection to `path`, in the string log format, and
+    ///makes sure it reached the disk.
+    pub fn save_to<P: AsRef<Path>>(&self, path: P) -> Result<(), LogError> {
+        let file = fs::File::create(path).map_err(LogError::FileCreateError)?;
+        let mut writer = BufWriter::new(file);
+
+        Self::write_all_entries(&self.0, &mut writer)?;
+        writer.flush().map_err(LogError::FlushError)?;
+        writer.get_ref().sync_all().map_err(LogError::SyncError)
+    }
+
+    ///Starts logging strings into a new file at `path`. Strings that were
+    ///already in the collection are written first.
+    pub fn open_log<P: AsRef<Path>>(&mut self, path: P) -> Result<(), LogError> {
+        let file = fs::File::create(path.as_ref()).map_err(LogError::FileCreateError)?;
+        let mut writer = BufWriter::new(file);
+
+        Self::write_all_entries(&self.0, &mut writer)?;
+        self.1 = Some(StringLog { writer, path: path.as_ref().to_path_buf() });
+        Ok(())
+    }
+
+    ///Rewrites the string log with what the collection currently holds. The
+    ///new log is written next to the old one, and then replaces it atomically.
+    fn compact_log(&mut self) -> Result<(), LogError> {
+        let path = match self.1.as_ref() {
+            Some(log) => log.path.clone(),
+            None      => return Ok(())
+        };
+
+        let tmp_path = suffixed(&path, ".tmp");
+
+        if let Err(err) = self.save_to(&tmp_path) {
+            fs::remove_file(&tmp_path).ok();
+            return Err(err);
+        }
+
+        fs::rename(&tmp_path, &path).map_err(LogError::RenameError)?;
+
+        //The old writer refers to the replaced file
+        self.1 = None;
+        let file = fs::OpenOptions::new().append(true).open(&path).map_err(LogError::FileOpenError)?;
+        self.1 = Some(StringLog { writer: BufWriter::new(file), path });
+
+        Ok(())
+    }
+
+    ///Makes sure every string inserted so far reached the string log
+    pub fn flush_log(&mut self) -> Result<(), LogError> {
+        match self.1.as_mut() {
+            Some(log) => log.writer.flush().map_err(LogError::FlushError),
+            None      => Ok(())
+        }
+    }
+
+    fn write_all_entries(internal: &Internal, writer: &mut BufWriter<fs::File>) -> Result<(), LogError> {
+        let map = internal.map.read().unwrap();
+
+        for (k, versions) in map.iter() {
+            for version in versions {
+                let s = unsafe { version.entry.as_str() };
+                bincode::serialize_into(&mut *writer, &(k, version.since, s)).map_err(LogError::SerializeError)?;
+            }
+        }
+
+        Ok(())
     }
 
     #[inline]
@@ -72,13 +323,54 @@ impl StringCollection
         }
     }
 
-    pub fn insert(&mut self, k: Key, v: &str) {
-        let v_bytes = v.as_bytes();
-        if v_bytes.len() >= POOL_SIZE {
-            panic!("string is too long to be inserted in StringCollection");
+    fn store_large(&mut self, v_bytes: &[u8]) -> Entry {
+        let large = unsafe { &mut *self.0.large.get() }; //Safe because only StringCollection writes in large
+        let bytes: Box<[u8]> = v_bytes.into();
+
+        let result = Entry {
+            ptr: bytes.as_ptr(), //Same as pools, these are **NEVER** dropped, and moving the box doesn't move its contents
+            len: bytes.len()
+        };
+
+        large.push(bytes);
+        self.0.metrics.large.fetch_add(1, Ordering::Relaxed);
+        result
+    }
+
+    ///Inserts `v` as the value of `k` starting from time `t`. Nothing
+    ///happens if `k` already maps to `v` at that time.
+    #[inline]
+    pub fn insert(&mut self, k: Key, v: &str, t: u64) {
+        self.insert_bytes(k, v.as_bytes(), t);
+    }
+
+    ///Inserts a string that may not be valid UTF-8. Invalid sequences
+    ///are replaced by U+FFFD.
+    pub fn insert_bytes(&mut self, k: Key, v: &[u8], t: u64) {
+        if v.len() > MAX_STRING_SIZE {
+            warn!("Rejecting string {:?} because it is too long ({} bytes)", k, v.len());
+            self.0.metrics.rejected.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+
+        let v = match std::str::from_utf8(v) {
+            Ok(s)  => Cow::Borrowed(s),
+            Err(_) => {
+                self.0.metrics.invalid_utf8.fetch_add(1, Ordering::Relaxed);
+                String::from_utf8_lossy(v)
+            }
+        };
+
+        let current = self.0.map.read().unwrap().get(&k).and_then(|versions| find_version(versions, t).copied());
+
+        if current.map(|version| unsafe { version.entry.as_str() } == v).unwrap_or(false) {
+            return;
         }
 
-        if !self.0.map.read().unwrap().contains_key(&k) {
+        let v_bytes = v.as_bytes();
+        let result = if v_bytes.len() >= LARGE_STRING_SIZE {
+            self.store_large(v_bytes)
+        } else {
             let pool = self.get_or_create_pool(v_bytes.len());
 
             let result = unsafe {
@@ -92,23 +384,136 @@ impl StringCollection
             };
 
             pool.pos += v_bytes.len();
-            self.0.map.write().unwrap().insert(k, result);
+            result
+        };
+
+        let version = Version { since: t, entry: result };
+        let mut map = self.0.map.write().unwrap();
+        let versions = map.entry(k).or_insert_with(Vec::new);
+        let pos = versions.iter().rposition(|x| x.since <= t).map(|i| i + 1).unwrap_or(0);
+
+        versions.insert(pos, version);
+        drop(map);
+
+        if let Some(log) = self.1.as_mut() {
+            if let Err(err) = bincode::serialize_into(&mut log.writer, &(k, t, &*v)) {
+                warn!("Failed to append string to string log, disabling it: {}", err);
+                self.1 = None;
+            }
         }
     }
+
+    ///Forgets the versions that were replaced before `t`, as well as every
+    ///key for which `is_unused` returns true. This is meant to be called once
+    ///entries older than `t` have been dropped. Returns how many versions were
+    ///forgotten. If any, the string log is rewritten without them.
+    ///
+    ///Note that this only removes map entries: strings that were already
+    ///returned by an `Accessor` remain valid.
+    pub fn forget_before<Func: FnMut(Key) -> bool>(&mut self, t: u64, mut is_unused: Func) -> usize {
+        let mut map = self.0.map.write().unwrap();
+        let mut forgotten = 0;
+
+        map.retain(|&k, versions| {
+            if is_unused(k) {
+                forgotten += versions.len();
+                return false;
+            }
+
+            let superseded = versions.iter().skip(1).take_while(|x| x.since <= t).count();
+            versions.drain(..superseded);
+            forgotten += superseded;
+            true
+        });
+
+        drop(map);
+
+        if forgotten > 0 {
+            if let Err(err) = self.compact_log() {
+                warn!("Failed to compact string log: {:?}", err);
+            }
+        }
+
+        forgotten
+    }
 }
 
 unsafe impl Send for StringCollection {} //No problem whatsoever
 
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count += n as u64;
+
+        Ok(n)
+    }
+}
+
+///Returns `path` with `suffix` appended to its file name
+fn suffixed(path: &Path, suffix: &str) -> PathBuf {
+    let mut ret = OsString::from(path);
+    ret.push(suffix);
+
+    ret.into()
+}
+
+impl Entry {
+    ///Unsafe because the caller has to make sure the lifetime of the
+    ///string doesn't exceed the lifetime of the StringCollection
+    unsafe fn as_str<'a>(&self) -> &'a str {
+        std::str::from_utf8_unchecked(std::slice::from_raw_parts(self.ptr, self.len)) //Strings are validated upon insertion
+    }
+}
+
+///Returns the version valid at `t`. If `t` is older than the first
+///version, then the first version is returned anyway.
+fn find_version(versions: &[Version], t: u64) -> Option<&Version> {
+    versions.iter().rev().find(|x| x.since <= t).or(versions.first())
+}
+
 impl Accessor {
+    pub fn get_stats(&self) -> Stats {
+        let metrics = &self.0.metrics;
+
+        Stats {
+            strings: self.0.map.read().unwrap().len(),
+            large: metrics.large.load(Ordering::Relaxed),
+            invalid_utf8: metrics.invalid_utf8.load(Ordering::Relaxed),
+            rejected: metrics.rejected.load(Ordering::Relaxed)
+        }
+    }
+
+    ///Returns the latest version of the string
     pub fn get<'a>(&'a self, k: Key) -> Option<&'a str> {
-        let opt_entry = self.0.map.read().unwrap().get(&k).copied();
-        
-        opt_entry.map(|entry| {
-            unsafe {
-                let s = std::slice::from_raw_parts(entry.ptr, entry.len);
-                std::str::from_utf8_unchecked(s)
+        let opt_entry = self.0.map.read().unwrap().get(&k).and_then(|versions| versions.last()).map(|version| version.entry);
+        opt_entry.map(|entry| unsafe { entry.as_str() })
+    }
+
+    ///Returns the version of the string that was valid at time `t`
+    pub fn get_at<'a>(&'a self, k: Key, t: u64) -> Option<&'a str> {
+        let opt_entry = self.0.map.read().unwrap().get(&k).and_then(|versions| find_version(versions, t)).map(|version| version.entry);
+        opt_entry.map(|entry| unsafe { entry.as_str() })
+    }
+
+    ///Returns every version of the string, along with the time they became valid
+    pub fn get_versions<'a>(&'a self, k: Key) -> Vec<(u64, &'a str)> {
+        match self.0.map.read().unwrap().get(&k) {
+            Some(versions) => versions.iter().map(|version| (version.since, unsafe { version.entry.as_str() })).collect(),
+            None           => Vec::new()
+        }
+    }
+
+    ///Calls `callback` for the latest version of every string in the collection,
+    ///in no particular order. Careful: the collection cannot be updated while
+    ///this function runs.
+    pub fn for_each<'a, Func: FnMut(Key, &'a str)>(&'a self, mut callback: Func) {
+        let map = self.0.map.read().unwrap();
+
+        for (&k, versions) in map.iter() {
+            if let Some(version) = versions.last() {
+                callback(k, unsafe { version.entry.as_str() });
             }
-        })
+        }
     }
 
     ///Same as `get()` except it assumes the lifetime of the string is static.
@@ -117,12 +522,8 @@ impl Accessor {
     ///the StringCollection instance won't be destroyed, this might be a faster
     ///option.
     pub unsafe fn get_static(&self, k: Key) -> Option<&'static str> {
-        let opt_entry = self.0.map.read().unwrap().get(&k).copied();
-        
-        opt_entry.map(|entry| {
-            let s = std::slice::from_raw_parts(entry.ptr, entry.len);
-            std::str::from_utf8_unchecked(s)
-        })
+        let opt_entry = self.0.map.read().unwrap().get(&k).and_then(|versions| versions.last()).map(|version| version.entry);
+        opt_entry.map(|entry| entry.as_str())
     }
 }
 
@@ -145,3 +546,236 @@ impl Clone for Accessor {
 
 unsafe impl Send for Accessor {} //No problem whatsoever
 unsafe impl Sync for Accessor {} //Accessor only touches map, which is a safe RwLock
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NAME: Key = Key::StaticString(0x1000);
+    const THREAD: Key = Key::ThreadName(0x1000);
+
+    fn log_path(test: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("temporal-lens-strings-{}-{}.log", test, std::process::id()))
+    }
+
+    #[test]
+    fn versions_are_looked_up_by_time() {
+        let mut strings = StringCollection::new();
+        let accessor = strings.new_accessor();
+
+        strings.insert(NAME, "first", 100);
+        strings.insert(NAME, "second", 200);
+        strings.insert(NAME, "second", 250); //Same string, no new version
+        strings.insert(THREAD, "main", 150); //Same address, different kind of key
+
+        assert_eq!(accessor.get_at(NAME, 0), Some("first")); //Older than the first version
+        assert_eq!(accessor.get_at(NAME, 100), Some("first"));
+        assert_eq!(accessor.get_at(NAME, 199), Some("first"));
+        assert_eq!(accessor.get_at(NAME, 200), Some("second"));
+        assert_eq!(accessor.get_at(NAME, 1000), Some("second"));
+        assert_eq!(accessor.get(NAME), Some("second"));
+        assert_eq!(accessor.get_versions(NAME), vec![(100, "first"), (200, "second")]);
+        assert_eq!(accessor.get_at(THREAD, 0), Some("main"));
+        assert_eq!(accessor.get(Key::StaticString(0x2000)), None);
+    }
+
+    ///Keys are addresses that may be reused: going back to an older string
+    ///must create a new version instead of being mistaken for a duplicate.
+    #[test]
+    fn old_name_can_come_back_after_rename() {
+        let mut strings = StringCollection::new();
+        let accessor = strings.new_accessor();
+
+        strings.insert(THREAD, "worker", 10);
+        strings.insert(THREAD, "renamed", 20);
+        strings.insert(THREAD, "worker", 30);
+
+        assert_eq!(accessor.get_versions(THREAD), vec![(10, "worker"), (20, "renamed"), (30, "worker")]);
+        assert_eq!(accessor.get_at(THREAD, 25), Some("renamed"));
+        assert_eq!(accessor.get_at(THREAD, 35), Some("worker"));
+
+        //Inserted late, but still sorted by time
+        strings.insert(THREAD, "early", 15);
+        assert_eq!(accessor.get_versions(THREAD), vec![(10, "worker"), (15, "early"), (20, "renamed"), (30, "worker")]);
+    }
+
+    #[test]
+    fn invalid_utf8_is_replaced() {
+        let mut strings = StringCollection::new();
+        let accessor = strings.new_accessor();
+
+        strings.insert_bytes(NAME, b"zone \xF0\x28\x8C\x28", 0);
+
+        assert_eq!(accessor.get(NAME), Some("zone \u{FFFD}(\u{FFFD}("));
+        assert_eq!(accessor.get_stats().invalid_utf8, 1);
+
+        //The same invalid bytes again are a duplicate of the replaced string
+        strings.insert_bytes(NAME, b"zone \xF0\x28\x8C\x28", 10);
+        assert_eq!(accessor.get_versions(NAME).len(), 1);
+    }
+
+    #[test]
+    fn large_strings_and_full_pools() {
+        let mut strings = StringCollection::new();
+        let accessor = strings.new_accessor();
+        let large = "x".repeat(LARGE_STRING_SIZE);
+        let too_large = vec![b'y'; MAX_STRING_SIZE + 1];
+
+        //Enough small strings to fill several pools
+        let small: Vec<String> = (0..3 * POOL_SIZE / 16).map(|i| format!("string #{:08}", i)).collect();
+
+        for (i, s) in small.iter().enumerate() {
+            strings.insert(Key::StaticString(i), s, 0);
+        }
+
+        strings.insert(NAME, &large, 0);
+        strings.insert_bytes(THREAD, &too_large, 0);
+
+        for (i, s) in small.iter().enumerate() {
+            assert_eq!(accessor.get(Key::StaticString(i)), Some(s.as_str()));
+        }
+
+        assert_eq!(accessor.get(NAME), Some(large.as_str()));
+        assert_eq!(accessor.get(THREAD), None);
+
+        let stats = accessor.get_stats();
+        assert_eq!(stats.large, 1);
+        assert_eq!(stats.rejected, 1);
+        assert_eq!(stats.strings, small.len() + 1);
+    }
+
+    #[test]
+    fn forget_before_keeps_the_version_valid_at_t() {
+        let mut strings = StringCollection::new();
+        let accessor = strings.new_accessor();
+
+        strings.insert(NAME, "a", 10);
+        strings.insert(NAME, "b", 20);
+        strings.insert(NAME, "c", 30);
+        strings.insert(THREAD, "unused", 10);
+
+        assert_eq!(strings.forget_before(25, |k| k == THREAD), 2);
+        assert_eq!(accessor.get_versions(NAME), vec![(20, "b"), (30, "c")]);
+        assert_eq!(accessor.get(THREAD), None);
+
+        //Nothing was replaced before 20
+        assert_eq!(strings.forget_before(20, |_| false), 0);
+        assert_eq!(accessor.get_at(NAME, 0), Some("b"));
+    }
+
+    ///The log written while inserting must load back to the same collection,
+    ///even if the server died while writing its last record.
+    #[test]
+    fn string_log_survives_truncation() {
+        let path = log_path("truncated");
+        let large = "z".repeat(LARGE_STRING_SIZE + 1);
+
+        {
+            let mut strings = StringCollection::new();
+            strings.insert(NAME, "first", 100);
+            strings.open_log(&path).unwrap(); //Already inserted strings are written too
+            strings.insert(NAME, "second", 200);
+            strings.insert(THREAD, &large, 300);
+            strings.insert(THREAD, "last", 400);
+            strings.flush_log().unwrap();
+        }
+
+        let loaded = StringCollection::load(&path).unwrap();
+        let accessor = loaded.new_accessor();
+
+        assert_eq!(accessor.get_versions(NAME), vec![(100, "first"), (200, "second")]);
+        assert_eq!(accessor.get_versions(THREAD), vec![(300, large.as_str()), (400, "last")]);
+        drop(accessor);
+        drop(loaded);
+
+        //Cut the last record in the middle of its string
+        let len = fs::metadata(&path).unwrap().len();
+        fs::OpenOptions::new().write(true).open(&path).unwrap().set_len(len - 2).unwrap();
+
+        let loaded = StringCollection::load(&path).unwrap();
+        let accessor = loaded.new_accessor();
+
+        assert_eq!(accessor.get_versions(NAME), vec![(100, "first"), (200, "second")]);
+        assert_eq!(accessor.get_versions(THREAD), vec![(300, large.as_str())]);
+
+        fs::remove_file(&path).ok();
+    }
+
+    ///Recovering a log must keep appending to it instead of starting over: a
+    ///torn last record is cut off, and a corrupted log is moved aside.
+    #[test]
+    fn recovered_log_is_appended_to() {
+        let path = log_path("recovered");
+        let aside = suffixed(&path, ".corrupted");
+        fs::remove_file(&aside).ok();
+
+        {
+            let mut strings = StringCollection::new();
+            strings.open_log(&path).unwrap();
+            strings.insert(NAME, "first", 100); //33 bytes long
+            strings.insert(THREAD, "main", 100);
+            strings.flush_log().unwrap();
+        }
+
+        let len = fs::metadata(&path).unwrap().len();
+        fs::OpenOptions::new().write(true).open(&path).unwrap().set_len(len - 1).unwrap();
+
+        {
+            let mut strings = StringCollection::recover_log(&path).unwrap();
+            let accessor = strings.new_accessor();
+
+            assert_eq!(accessor.get(NAME), Some("first"));
+            assert_eq!(accessor.get(THREAD), None);
+
+            strings.insert(NAME, "second", 200);
+            strings.flush_log().unwrap();
+        }
+
+        let loaded = StringCollection::load(&path).unwrap();
+        assert_eq!(loaded.new_accessor().get_versions(NAME), vec![(100, "first"), (200, "second")]);
+        drop(loaded);
+
+        //Invalid key variant in the second record
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[33] = 0xFF;
+        fs::write(&path, &bytes).unwrap();
+
+        let strings = StringCollection::recover_log(&path).unwrap();
+        assert_eq!(strings.new_accessor().get_versions(NAME), vec![(100, "first")]);
+        drop(strings);
+
+        assert_eq!(fs::read(&aside).unwrap(), bytes);
+        assert_eq!(StringCollection::load(&path).unwrap().new_accessor().get_versions(NAME), vec![(100, "first")]);
+
+        fs::remove_file(&path).ok();
+        fs::remove_file(&aside).ok();
+    }
+
+    #[test]
+    fn forget_before_compacts_the_log() {
+        let path = log_path("compacted");
+        let mut strings = StringCollection::new();
+
+        strings.open_log(&path).unwrap();
+        strings.insert(NAME, &"a".repeat(100), 10);
+        strings.insert(NAME, "b", 20);
+        strings.insert(THREAD, "unused", 10);
+        strings.flush_log().unwrap();
+
+        let len = fs::metadata(&path).unwrap().len();
+        assert_eq!(strings.forget_before(25, |k| k == THREAD), 2);
+        assert!(fs::metadata(&path).unwrap().len() < len);
+
+        //Still logging, into the compacted file
+        strings.insert(THREAD, "main", 30);
+        strings.flush_log().unwrap();
+
+        let loaded = StringCollection::load(&path).unwrap();
+        let accessor = loaded.new_accessor();
+
+        assert_eq!(accessor.get_versions(NAME), vec![(20, "b")]);
+        assert_eq!(accessor.get_versions(THREAD), vec![(30, "main")]);
+
+        fs::remove_file(&path).ok();
+    }
+}
@@ -0,0 +1,242 @@
+use crate::string_collection::{Accessor as SCAccessor, Key as SCKey};
+use crate::catalogue::{Accessor as CatalogueAccessor, ThreadInfo};
+use crate::common::{LiteZoneData, ZoneKey};
+
+use rocket::request::{FromQuery, Query};
+use fxhash::FxHashSet;
+
+///Zone filters as sent by the client, i.e. the remaining query items
+///of zone endpoints. `thread` and `name` are thread and zone names, while
+///`thread_id` and `name_id` are their keys. All of them can be repeated.
+pub struct ZoneFilterParams
+{
+    threads: Vec<String>,
+    thread_ids: Vec<usize>,
+    names: Vec<String>,
+    name_ids: Vec<usize>,
+    max_depth: Option<u32>
+}
+
+///Resolved version of `ZoneFilterParams`
+pub struct ZoneFilter
+{
+    threads: Option<FxHashSet<usize>>,
+    names: Option<FxHashSet<usize>>,
+    max_depth: Option<u32>
+}
+
+impl<'q> FromQuery<'q> for ZoneFilterParams {
+    type Error = String;
+
+    fn from_query(query: Query<'q>) -> Result<Self, String> {
+        let mut ret = ZoneFilterParams {
+            threads: Vec::new(),
+            thread_ids: Vec::new(),
+            names: Vec::new(),
+            name_ids: Vec::new(),
+            max_depth: None
+        };
+
+        for item in query {
+            let (key, value) = item.key_value_decoded();
+
+            match key.as_str() {
+                "thread"    => ret.threads.push(value),
+                "thread_id" => ret.thread_ids.push(value.parse().map_err(|_| format!("invalid thread_id \"{}\"", value))?),
+                "name"      => ret.names.push(value),
+                "name_id"   => ret.name_ids.push(value.parse().map_err(|_| format!("invalid name_id \"{}\"", value))?),
+                "max_depth" => ret.max_depth = Some(value.parse().map_err(|_| format!("invalid max_depth \"{}\"", value))?),
+                _           => {}
+            }
+        }
+
+        Ok(ret)
+    }
+}
+
+impl ZoneFilterParams {
+    ///Parses a query string the way Rocket does for endpoints
+    #[cfg(test)]
+    pub fn parse(query: &str) -> Result<Self, String> {
+        let items: Vec<_> = rocket::request::FormItems::from(query).collect();
+        Self::from_query(Query(&items))
+    }
+
+    ///Turns thread and zone names into keys. Names that match no known
+    ///thread or zone are ignored, which means the filter might end up
+    ///matching nothing.
+    pub fn resolve(&self, str_collection: &SCAccessor, threads: &CatalogueAccessor<ThreadInfo>) -> ZoneFilter {
+        let thread_set = if self.threads.is_empty() && self.thread_ids.is_empty() {
+            None
+        } else {
+            let known = threads.list();
+            let mut set: FxHashSet<usize> = self.thread_ids.iter().copied().collect();
+
+            for t in &self.threads {
+                set.extend(known.iter().map(|&(key, _)| key).filter(|&key| str_collection.get(SCKey::ThreadName(key)) == Some(t.as_str())));
+            }
+
+            Some(set)
+        };
+
+        let name_set = if self.names.is_empty() && self.name_ids.is_empty() {
+            None
+        } else {
+            let mut set: FxHashSet<usize> = self.name_ids.iter().copied().collect();
+
+            str_collection.for_each(|k, s| match k {
+                SCKey::StaticString(key) if self.names.iter().any(|n| n == s) => { set.insert(key); },
+                _ => {}
+            });
+
+            Some(set)
+        };
+
+        ZoneFilter {
+            threads: thread_set,
+            names: name_set,
+            max_depth: self.max_depth
+        }
+    }
+}
+
+impl ZoneFilter {
+    #[inline]
+    pub fn matches(&self, zone: &LiteZoneData) -> bool {
+        self.matches_span(zone.thread, zone.depth) && self.matches_name(zone.name)
+    }
+
+    #[inline]
+    pub fn matches_name(&self, name: usize) -> bool {
+        self.names.as_ref().map(|set| set.contains(&name)).unwrap_or(true)
+    }
+
+    ///Same as `matches()`, but ignores zone names. Only meant for summaries
+    ///that don't keep track of names, and thus useless if `has_names()`.
+    #[inline]
+    pub fn matches_span(&self, thread: usize, depth: u32) -> bool {
+        self.max_depth.map(|d| depth <= d).unwrap_or(true) && self.threads.as_ref().map(|set| set.contains(&thread)).unwrap_or(true)
+    }
+
+    #[inline]
+    pub fn has_names(&self) -> bool {
+        self.names.is_some()
+    }
+
+    ///Returns the keys to look for in the `zone_db` index, if the filter
+    ///restricts zone names or threads. Names are preferred as they are
+    ///usually more selective.
+    pub fn index_keys(&self) -> Option<Vec<ZoneKey>> {
+        match (&self.names, &self.threads) {
+            (Some(names), _)      => Some(names.iter().map(|&key| ZoneKey::Name(key)).collect()),
+            (None, Some(threads)) => Some(threads.iter().map(|&key| ZoneKey::Thread(key)).collect()),
+            (None, None)          => None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::string_collection::StringCollection;
+    use crate::catalogue::Catalogue;
+
+    ///Resolves `query` against threads 1 to 3, named "main", "worker" and "worker",
+    ///and zones 10 and 11, named "update" and "draw"
+    fn resolve(query: &str) -> ZoneFilter {
+        let mut strings = StringCollection::new();
+        let mut threads = Catalogue::new();
+
+        for &(key, name) in &[(1, "main"), (2, "worker"), (3, "worker")] {
+            strings.insert(SCKey::ThreadName(key), name, 0);
+            threads.record(key, || ThreadInfo::new(0, 1), |t| t.add(0, 1));
+        }
+
+        strings.insert(SCKey::StaticString(10), "update", 0);
+        strings.insert(SCKey::StaticString(11), "draw", 0);
+
+        ZoneFilterParams::parse(query).unwrap().resolve(&strings.new_accessor(), &threads.new_accessor())
+    }
+
+    fn sorted(set: &Option<FxHashSet<usize>>) -> Option<Vec<usize>> {
+        set.as_ref().map(|set| {
+            let mut ret: Vec<_> = set.iter().copied().collect();
+            ret.sort_unstable();
+            ret
+        })
+    }
+
+    fn zone(thread: usize, name: usize, depth: u32) -> LiteZoneData {
+        LiteZoneData {
+            uid: 0,
+            duration: 0,
+            name,
+            thread,
+            color: 0,
+            depth
+        }
+    }
+
+    #[test]
+    fn names_resolve_to_keys() {
+        let filter = resolve("name=draw&name=unknown&name_id=12");
+        assert_eq!(sorted(&filter.names), Some(vec![11, 12]));
+        assert_eq!(sorted(&filter.threads), None);
+        assert!(filter.has_names());
+        assert!(filter.matches(&zone(1, 11, 5)));
+        assert!(!filter.matches(&zone(1, 10, 0)));
+
+        //Unknown names match nothing rather than everything
+        let filter = resolve("name=unknown");
+        assert_eq!(sorted(&filter.names), Some(vec![]));
+        assert_eq!(filter.index_keys(), Some(vec![]));
+        assert!(!filter.matches(&zone(1, 10, 0)));
+    }
+
+    #[test]
+    fn repeated_threads_add_up() {
+        //Both threads named "worker" are selected
+        let filter = resolve("thread=main&thread=worker");
+        assert_eq!(sorted(&filter.threads), Some(vec![1, 2, 3]));
+        assert!(!filter.has_names());
+        assert!(filter.matches_span(3, 0));
+        assert!(!filter.matches_span(4, 0));
+
+        let filter = resolve("thread=worker&thread_id=4&thread=unknown");
+        assert_eq!(sorted(&filter.threads), Some(vec![2, 3, 4]));
+        assert!(!filter.matches(&zone(1, 10, 0)));
+
+        let mut keys = filter.index_keys().unwrap();
+        keys.sort_by_key(|k| match k { ZoneKey::Thread(t) => *t, ZoneKey::Name(_) => panic!("{:?} isn't a thread", k) });
+        assert_eq!(keys, vec![ZoneKey::Thread(2), ZoneKey::Thread(3), ZoneKey::Thread(4)]);
+
+        //Names are preferred, threads are still checked
+        let filter = resolve("thread=main&name=update");
+        assert_eq!(filter.index_keys(), Some(vec![ZoneKey::Name(10)]));
+        assert!(filter.matches(&zone(1, 10, 0)));
+        assert!(!filter.matches(&zone(2, 10, 0)));
+    }
+
+    #[test]
+    fn max_depth_applies_with_and_without_index() {
+        let filter = resolve("max_depth=1");
+        assert_eq!(filter.index_keys(), None);
+        assert!(filter.matches(&zone(1, 10, 1)));
+        assert!(!filter.matches(&zone(1, 10, 2)));
+        assert!(filter.matches_span(2, 0));
+        assert!(!filter.matches_span(2, 2));
+
+        let filter = resolve("name=update&max_depth=1");
+        assert_eq!(filter.index_keys(), Some(vec![ZoneKey::Name(10)]));
+        assert!(filter.matches(&zone(1, 10, 1)));
+        assert!(!filter.matches(&zone(1, 10, 2)));
+    }
+
+    #[test]
+    fn invalid_values_are_rejected() {
+        assert!(ZoneFilterParams::parse("thread_id=main").is_err());
+        assert!(ZoneFilterParams::parse("name_id=-1").is_err());
+        assert!(ZoneFilterParams::parse("max_depth=deep").is_err());
+        assert!(ZoneFilterParams::parse("start=0&end=10").is_ok()); //Other parameters of the endpoint
+    }
+}